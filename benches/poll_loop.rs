@@ -0,0 +1,107 @@
+use std::time::{Duration, Instant};
+
+use agents::*;
+use criterion::{criterion_group, criterion_main, Criterion};
+use futures::channel::mpsc::{channel, Receiver, Sender};
+use futures::executor::LocalPool;
+use futures::future::FutureExt;
+use futures::stream::{self, StreamExt};
+use futures::task::LocalSpawnExt;
+
+const MESSAGES: usize = 1000;
+
+struct Forward {
+    output: Output<usize>,
+}
+
+fn forward_agent(receivers: Vec<Receiver<usize>>, sender: Sender<usize>) -> Agent<Forward> {
+    let mut builder = Builder::new();
+    let output = builder.new_output(sender);
+    for r in receivers {
+        builder.new_input(r, |s: &mut Forward, v| s.output.send(v), |_: &mut Forward| ());
+    }
+    builder.finish(Forward { output })
+}
+
+fn run_fan_in(pool: &mut LocalPool, inputs: usize) {
+    let mut senders = Vec::new();
+    let mut receivers = Vec::new();
+    for _ in 0..inputs {
+        let (tx, rx) = channel(16);
+        senders.push(tx);
+        receivers.push(rx);
+    }
+    let (tx, rx) = channel(16);
+    let spawner = pool.spawner();
+    spawner.spawn_local(forward_agent(receivers, tx).map(drop)).unwrap();
+
+    let per_input = MESSAGES / inputs;
+    for s in senders {
+        spawner
+            .spawn_local(stream::iter(0..per_input).map(Ok).forward(s).map(drop))
+            .unwrap();
+    }
+    pool.run_until(rx.take(per_input * inputs).for_each(|_| async {}));
+}
+
+fn passthrough(c: &mut Criterion) {
+    let mut pool = LocalPool::new();
+    c.bench_function("passthrough 1 input 1 output", |b| {
+        b.iter(|| run_fan_in(&mut pool, 1))
+    });
+}
+
+fn fan_in(c: &mut Criterion) {
+    let mut pool = LocalPool::new();
+    c.bench_function("fan-in 64 inputs", |b| b.iter(|| run_fan_in(&mut pool, 64)));
+}
+
+struct Idle;
+
+fn timer_agent(clock: &MockClock, timers: usize) -> Agent<Idle> {
+    let mut builder = Builder::new();
+    // The periods repeat, so most activations land before ones already queued.
+    for i in 0..timers {
+        builder.new_timer(
+            clock.handle(),
+            Duration::from_millis(1 + (i % 100) as u64),
+            |_: &mut Idle| TimerRun::Continue,
+        );
+    }
+    builder.finish(Idle)
+}
+
+fn timer_scheduling(c: &mut Criterion) {
+    c.bench_function("schedule 10k timers", |b| {
+        b.iter(|| {
+            let mut pool = LocalPool::new();
+            let clock = MockClock::new(Instant::now());
+            pool.spawner().spawn_local(timer_agent(&clock, 10_000).map(drop)).unwrap();
+            pool.run_until_stalled();
+        })
+    });
+}
+
+fn dense_activations(c: &mut Criterion) {
+    c.bench_function("advance MockClock with 1k dense timers", |b| {
+        b.iter(|| {
+            let mut pool = LocalPool::new();
+            let mut clock = MockClock::new(Instant::now());
+            pool.spawner().spawn_local(timer_agent(&clock, 1000).map(drop)).unwrap();
+            pool.run_until_stalled();
+            for _ in 0..100 {
+                clock.advance(Duration::from_millis(1));
+                pool.run_until_stalled();
+            }
+        })
+    });
+}
+
+criterion_group!(
+    benches,
+    passthrough,
+    fan_in,
+    timer_scheduling,
+    dense_activations
+);
+criterion_main!(benches);
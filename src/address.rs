@@ -0,0 +1,29 @@
+use futures::channel::mpsc::UnboundedSender;
+
+/// A cloneable handle to send messages into an agent, see
+/// `Builder::new_mailbox` and `Builder::self_mailbox`.
+///
+/// Sending never waits, so an agent can safely send to its own address from
+/// a handler.
+pub struct Address<T> {
+    sender: UnboundedSender<T>,
+}
+
+impl<T> Address<T> {
+    pub(crate) fn new(sender: UnboundedSender<T>) -> Address<T> {
+        Address { sender }
+    }
+
+    /// Queues `message`, or hands it back if the agent is gone.
+    pub fn send(&self, message: T) -> Result<(), T> {
+        self.sender.unbounded_send(message).map_err(|e| e.into_inner())
+    }
+}
+
+impl<T> Clone for Address<T> {
+    fn clone(&self) -> Address<T> {
+        Address {
+            sender: self.sender.clone(),
+        }
+    }
+}
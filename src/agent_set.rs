@@ -0,0 +1,141 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use crate::{Agent, ClockHandle};
+
+type BoxedAgent = Pin<Box<dyn Future<Output = Result<(), ()>>>>;
+
+// An agent of the set, which is not polled before `start`, if it has one.
+struct Member {
+    agent: BoxedAgent,
+    start: Option<Instant>,
+    armed: bool,
+}
+
+struct Stagger {
+    clock: ClockHandle,
+    interval: Duration,
+    next: Instant,
+}
+
+/// Owns several agents and drives them as a single future, so many small
+/// agents can share one executor task.
+///
+/// Agents are polled round-robin. With a budget, at most that many agents are
+/// polled per call; the rest get their turn on the next poll, which is
+/// scheduled immediately.
+pub struct AgentSet {
+    agents: Vec<Member>,
+    next: usize,
+    budget: usize,
+    stagger: Option<Stagger>,
+}
+
+impl AgentSet {
+    pub fn new() -> AgentSet {
+        AgentSet::with_budget(usize::MAX)
+    }
+
+    pub fn with_budget(budget: usize) -> AgentSet {
+        assert!(budget > 0, "AgentSet budget must be positive");
+        AgentSet {
+            agents: Vec::new(),
+            next: 0,
+            budget,
+            stagger: None,
+        }
+    }
+
+    /// Starts the agents added from now on one after another, `interval`
+    /// apart on `clock`, instead of all at once. The first of them starts
+    /// right away. Until its start an agent is not polled at all, so its
+    /// timers and connections are spread out as well.
+    pub fn stagger(&mut self, clock: ClockHandle, interval: Duration) {
+        let next = clock.now();
+        self.stagger = Some(Stagger {
+            clock,
+            interval,
+            next,
+        });
+    }
+
+    pub fn add<S: 'static>(&mut self, agent: Agent<S>) {
+        let start = self.stagger.as_mut().map(|s| {
+            let start = s.next.max(s.clock.now());
+            s.next = start + s.interval;
+            start
+        });
+        self.agents.push(Member {
+            agent: Box::pin(agent),
+            start,
+            armed: false,
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.agents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.agents.is_empty()
+    }
+}
+
+impl Default for AgentSet {
+    fn default() -> AgentSet {
+        AgentSet::new()
+    }
+}
+
+impl Future for AgentSet {
+    type Output = Result<(), ()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let count = self.agents.len().min(self.budget);
+        let mut finished = Vec::new();
+        let clock = self.stagger.as_ref().map(|s| s.clock.clone());
+        for i in 0..count {
+            let idx = (self.next + i) % self.agents.len();
+            let m = &mut self.agents[idx];
+            if let (Some(start), Some(clock)) = (m.start, &clock) {
+                if clock.now() < start {
+                    if !m.armed {
+                        clock.add_activation(cx.waker().clone(), start);
+                        m.armed = true;
+                    }
+                    continue;
+                }
+                m.start = None;
+            }
+            if let Poll::Ready(r) = m.agent.as_mut().poll(cx) {
+                r?;
+                finished.push(idx);
+            }
+        }
+
+        if count < self.agents.len() {
+            self.next = (self.next + count) % self.agents.len();
+            // Agents past the budget have not been polled yet.
+            cx.waker().wake_by_ref();
+        }
+
+        finished.sort_unstable_by(|a, b| b.cmp(a));
+        for idx in finished {
+            drop(self.agents.remove(idx));
+            if idx < self.next {
+                self.next -= 1;
+            }
+        }
+        if self.next >= self.agents.len() {
+            self.next = 0;
+        }
+
+        if self.agents.is_empty() {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use futures::channel::mpsc::{Receiver, Sender};
+
+use crate::{Agent, Builder, ClockHandle, LinkEnd, Output, TimerRun};
+
+/// What two `AntiEntropyAgent`s exchange over their link.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncMessage<K, V> {
+    /// The version of every entry the sender holds.
+    Digest(Vec<(K, u64)>),
+    /// Entries the receiver lacks or holds an older version of.
+    Entries(Vec<(K, u64, V)>),
+}
+
+/// Keeps a versioned keyed store in sync with a replica on the other end of
+/// a link.
+///
+/// Local writes arrive as `(key, version, value)` and the highest version of
+/// a key wins. Every `period` of clock time the agent sends the peer a digest
+/// of its versions, and answers the peer's digests with just the entries the
+/// peer is missing or holds an older version of. Every write that changes
+/// the store, local or from the peer, is reported on `changes`.
+///
+/// Shutdown is one-sided. Once the writes have ended, the agent pushes its
+/// whole store to the peer on its next period instead of a digest, then closes
+/// its side of the link. From then on it no longer answers the peer's digests
+/// or pulls entries from the peer; it only applies what the peer pushes when
+/// its own writes end, and finishes once the peer has closed its side as well.
+pub struct AntiEntropyAgent<K, V> {
+    store: BTreeMap<K, (u64, V)>,
+    peer: Output<SyncMessage<K, V>>,
+    changes: Output<(K, u64, V)>,
+    writes_ended: bool,
+}
+
+impl<K: Ord + Clone + 'static, V: Clone + 'static> AntiEntropyAgent<K, V> {
+    pub fn new(
+        clock: ClockHandle,
+        period: Duration,
+        writes: Receiver<(K, u64, V)>,
+        peer: LinkEnd<SyncMessage<K, V>, SyncMessage<K, V>>,
+        changes: Sender<(K, u64, V)>,
+    ) -> Agent<AntiEntropyAgent<K, V>> {
+        let mut builder = Builder::new();
+        let changes = builder.new_output(changes);
+        let peer = builder.new_link(
+            peer,
+            |s: &mut AntiEntropyAgent<K, V>, m| s.on_sync(m),
+            |_: &mut AntiEntropyAgent<K, V>| (),
+        );
+        builder.new_input(
+            writes,
+            |s: &mut AntiEntropyAgent<K, V>, (k, version, v)| s.write(k, version, v),
+            |s: &mut AntiEntropyAgent<K, V>| s.writes_ended = true,
+        );
+        builder.new_timer(clock, period, |s: &mut AntiEntropyAgent<K, V>| s.on_period());
+        builder.finish(AntiEntropyAgent {
+            store: BTreeMap::new(),
+            peer,
+            changes,
+            writes_ended: false,
+        })
+    }
+
+    fn write(&mut self, key: K, version: u64, value: V) {
+        if let Some(&(current, _)) = self.store.get(&key) {
+            if current >= version {
+                return;
+            }
+        }
+        self.changes.send((key.clone(), version, value.clone()));
+        self.store.insert(key, (version, value));
+    }
+
+    fn on_sync(&mut self, message: SyncMessage<K, V>) {
+        match message {
+            SyncMessage::Digest(digest) => {
+                let theirs: BTreeMap<K, u64> = digest.into_iter().collect();
+                let newer: Vec<(K, u64, V)> = self
+                    .store
+                    .iter()
+                    .filter(|&(k, &(version, _))| theirs.get(k).is_none_or(|&v| v < version))
+                    .map(|(k, &(version, ref v))| (k.clone(), version, v.clone()))
+                    .collect();
+                if !newer.is_empty() {
+                    self.peer.send(SyncMessage::Entries(newer));
+                }
+            }
+            SyncMessage::Entries(entries) => {
+                for (k, version, v) in entries {
+                    self.write(k, version, v);
+                }
+            }
+        }
+    }
+
+    fn on_period(&mut self) -> TimerRun {
+        if self.writes_ended {
+            let entries: Vec<(K, u64, V)> = self
+                .store
+                .iter()
+                .map(|(k, &(version, ref v))| (k.clone(), version, v.clone()))
+                .collect();
+            if !entries.is_empty() {
+                self.peer.send(SyncMessage::Entries(entries));
+            }
+            self.peer.close();
+            return TimerRun::Stop;
+        }
+        let digest = self.store.iter().map(|(k, &(version, _))| (k.clone(), version)).collect();
+        self.peer.send(SyncMessage::Digest(digest));
+        TimerRun::Continue
+    }
+}
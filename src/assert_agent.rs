@@ -0,0 +1,68 @@
+use std::fmt::Debug;
+
+use futures::channel::mpsc::{Receiver, Sender};
+
+use crate::{Agent, Builder, Output};
+
+type PredicateFn<T> = dyn FnMut(&T) -> bool;
+type ViolationFn<T> = dyn FnMut(u64, &T);
+
+/// Passthrough agent for tests that checks every message against a predicate.
+///
+/// Splice it into a link to catch a broken invariant at the hop where it first
+/// shows up. Messages are forwarded unchanged whether or not they pass.
+pub struct AssertAgent<T> {
+    output: Output<T>,
+    predicate: Box<PredicateFn<T>>,
+    on_violation: Box<ViolationFn<T>>,
+    count: u64,
+}
+
+impl<T: 'static> AssertAgent<T> {
+    /// `on_violation` is called with the zero-based position of the offending
+    /// message in the stream and the message itself.
+    pub fn new<P, V>(
+        receiver: Receiver<T>,
+        sender: Sender<T>,
+        predicate: P,
+        on_violation: V,
+    ) -> Agent<AssertAgent<T>>
+    where
+        P: FnMut(&T) -> bool + 'static,
+        V: FnMut(u64, &T) + 'static,
+    {
+        let mut builder = Builder::new();
+        let output = builder.new_output(sender);
+        builder.new_input(
+            receiver,
+            |s: &mut AssertAgent<T>, v: T| s.on_input(v),
+            |_: &mut AssertAgent<T>| (),
+        );
+        builder.finish(AssertAgent {
+            output,
+            predicate: Box::new(predicate),
+            on_violation: Box::new(on_violation),
+            count: 0,
+        })
+    }
+
+    fn on_input(&mut self, value: T) {
+        if !(self.predicate)(&value) {
+            (self.on_violation)(self.count, &value);
+        }
+        self.count += 1;
+        self.output.send(value);
+    }
+}
+
+impl<T: Debug + 'static> AssertAgent<T> {
+    /// Like `new`, but panics on the first message that fails the predicate.
+    pub fn panicking<P>(receiver: Receiver<T>, sender: Sender<T>, predicate: P) -> Agent<AssertAgent<T>>
+    where
+        P: FnMut(&T) -> bool + 'static,
+    {
+        AssertAgent::new(receiver, sender, predicate, |n, v: &T| {
+            panic!("message #{} failed assertion: {:?}", n, v)
+        })
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+use futures::channel::mpsc::{Receiver, Sender};
+
+use crate::{Agent, Builder, ClockHandle, Output, TimerRun};
+
+/// A difference found by `CompareAgent`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference<T> {
+    /// Both sides produced a message for the same key, but they differ.
+    Mismatch { primary: T, shadow: T },
+    /// The primary message found no shadow counterpart within the window.
+    MissingShadow(T),
+    /// The shadow message found no primary counterpart within the window.
+    MissingPrimary(T),
+}
+
+// Called with the message and its zero-based position in its own stream.
+type KeyFn<T, K> = dyn Fn(&T, u64) -> K;
+
+// Messages from one side still waiting for their counterpart, with arrival
+// times in arrival order for expiry.
+struct Pending<T, K> {
+    messages: HashMap<K, (Instant, T)>,
+    arrivals: VecDeque<(Instant, K)>,
+    count: u64,
+}
+
+impl<T, K: Hash + Eq + Clone> Pending<T, K> {
+    fn new() -> Pending<T, K> {
+        Pending {
+            messages: HashMap::new(),
+            arrivals: VecDeque::new(),
+            count: 0,
+        }
+    }
+
+    fn insert(&mut self, now: Instant, key: K, value: T) {
+        self.arrivals.push_back((now, key.clone()));
+        self.messages.insert(key, (now, value));
+    }
+
+    // Removes and returns the messages that arrived at or before `cutoff`.
+    fn expire(&mut self, cutoff: Instant) -> Vec<T> {
+        let mut expired = Vec::new();
+        while let Some(&(when, _)) = self.arrivals.front() {
+            if when > cutoff {
+                break;
+            }
+            let (when, key) = self.arrivals.pop_front().unwrap();
+            // The key may have been matched, or reused by a later message.
+            if self.messages.get(&key).map(|m| m.0) == Some(when) {
+                expired.push(self.messages.remove(&key).unwrap().1);
+            }
+        }
+        expired
+    }
+}
+
+/// Analysis half of shadow testing: pairs the messages of a primary and a
+/// shadow stream and reports every difference.
+///
+/// Messages are paired by a key, or by their position in each stream. A
+/// message whose counterpart does not arrive within `window` of clock time is
+/// reported as missing; expiry is checked once per window, so this happens
+/// between one and two windows after the message arrived. Once both streams
+/// have ended and every unpaired message has been reported, the agent
+/// finishes.
+pub struct CompareAgent<T, K> {
+    reports: Output<Difference<T>>,
+    clock: ClockHandle,
+    window: Duration,
+    key: Box<KeyFn<T, K>>,
+    primary: Pending<T, K>,
+    shadow: Pending<T, K>,
+    open_inputs: usize,
+}
+
+impl<T: PartialEq + 'static> CompareAgent<T, u64> {
+    /// Pairs the n-th primary message with the n-th shadow message.
+    pub fn by_sequence(
+        clock: ClockHandle,
+        window: Duration,
+        primary: Receiver<T>,
+        shadow: Receiver<T>,
+        reports: Sender<Difference<T>>,
+    ) -> Agent<CompareAgent<T, u64>> {
+        CompareAgent::build(clock, window, primary, shadow, reports, Box::new(|_, n| n))
+    }
+}
+
+impl<T: PartialEq + 'static, K: Hash + Eq + Clone + 'static> CompareAgent<T, K> {
+    /// Pairs messages with equal `key`. Keys are assumed unique per stream.
+    pub fn new<F: Fn(&T) -> K + 'static>(
+        clock: ClockHandle,
+        window: Duration,
+        primary: Receiver<T>,
+        shadow: Receiver<T>,
+        reports: Sender<Difference<T>>,
+        key: F,
+    ) -> Agent<CompareAgent<T, K>> {
+        CompareAgent::build(clock, window, primary, shadow, reports, Box::new(move |v, _| key(v)))
+    }
+
+    fn build(
+        clock: ClockHandle,
+        window: Duration,
+        primary: Receiver<T>,
+        shadow: Receiver<T>,
+        reports: Sender<Difference<T>>,
+        key: Box<KeyFn<T, K>>,
+    ) -> Agent<CompareAgent<T, K>> {
+        let mut builder = Builder::new();
+        let reports = builder.new_output(reports);
+        builder.new_input(
+            primary,
+            |s: &mut CompareAgent<T, K>, v: T| s.on_message(v, true),
+            |s: &mut CompareAgent<T, K>| s.open_inputs -= 1,
+        );
+        builder.new_input(
+            shadow,
+            |s: &mut CompareAgent<T, K>, v: T| s.on_message(v, false),
+            |s: &mut CompareAgent<T, K>| s.open_inputs -= 1,
+        );
+        builder.new_timer(clock.clone(), window, |s: &mut CompareAgent<T, K>| s.on_expiry());
+        builder.finish(CompareAgent {
+            reports,
+            clock,
+            window,
+            key,
+            primary: Pending::new(),
+            shadow: Pending::new(),
+            open_inputs: 2,
+        })
+    }
+
+    fn on_message(&mut self, value: T, is_primary: bool) {
+        let (own, other) = if is_primary {
+            (&mut self.primary, &mut self.shadow)
+        } else {
+            (&mut self.shadow, &mut self.primary)
+        };
+        let key = (self.key)(&value, own.count);
+        own.count += 1;
+        match other.messages.remove(&key) {
+            Some((_, counterpart)) => {
+                if counterpart != value {
+                    let (primary, shadow) = if is_primary {
+                        (value, counterpart)
+                    } else {
+                        (counterpart, value)
+                    };
+                    self.reports.send(Difference::Mismatch { primary, shadow });
+                }
+            }
+            None => own.insert(self.clock.now(), key, value),
+        }
+    }
+
+    fn on_expiry(&mut self) -> TimerRun {
+        let cutoff = self.clock.now() - self.window;
+        for v in self.primary.expire(cutoff) {
+            self.reports.send(Difference::MissingShadow(v));
+        }
+        for v in self.shadow.expire(cutoff) {
+            self.reports.send(Difference::MissingPrimary(v));
+        }
+        let done = self.primary.messages.is_empty() && self.shadow.messages.is_empty();
+        if self.open_inputs == 0 && done {
+            TimerRun::Stop
+        } else {
+            TimerRun::Continue
+        }
+    }
+}
@@ -0,0 +1,32 @@
+use futures::channel::mpsc::{channel, Receiver, Sender};
+
+/// One end of a link created by `duplex`: sends `Out` and receives `In`.
+///
+/// Plug it into an agent with `Builder::new_link`.
+pub struct LinkEnd<Out, In> {
+    pub(crate) sender: Sender<Out>,
+    pub(crate) receiver: Receiver<In>,
+}
+
+impl<Out, In> LinkEnd<Out, In> {
+    pub fn into_parts(self) -> (Sender<Out>, Receiver<In>) {
+        (self.sender, self.receiver)
+    }
+}
+
+/// A bidirectional link: the first end sends `A` to the second one, which
+/// answers with `B`. Each direction buffers `capacity` messages.
+pub fn duplex<A, B>(capacity: usize) -> (LinkEnd<A, B>, LinkEnd<B, A>) {
+    let (a_tx, a_rx) = channel(capacity);
+    let (b_tx, b_rx) = channel(capacity);
+    (
+        LinkEnd {
+            sender: a_tx,
+            receiver: b_rx,
+        },
+        LinkEnd {
+            sender: b_tx,
+            receiver: a_rx,
+        },
+    )
+}
@@ -0,0 +1,70 @@
+use std::any::Any;
+use std::error::Error;
+use std::fmt;
+
+/// Why an agent built with `Builder::finish_v2` stopped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AgentError {
+    /// The receiving end of an output was dropped, so a message could not be
+    /// delivered. Carries the output name, if it was given one.
+    OutputClosed(Option<String>),
+    /// An output bounded with `BackpressurePolicy::FailSend` was sent a message
+    /// while full. Carries the output name, if it was given one.
+    OutputOverflow(Option<String>),
+    /// A handler panicked. Carries the panic message.
+    Panic(String),
+    /// The shutdown timeout passed before the outputs were drained. Carries
+    /// the name, if any, and the number of messages still buffered of every
+    /// output that had some.
+    Unflushed(Vec<(Option<String>, usize)>),
+    /// The deadline set with `Agent::with_deadline` passed before the agent
+    /// finished.
+    Timeout,
+}
+
+impl AgentError {
+    pub(crate) fn from_panic(payload: Box<dyn Any + Send>) -> AgentError {
+        let message = if let Some(s) = payload.downcast_ref::<&str>() {
+            s.to_string()
+        } else if let Some(s) = payload.downcast_ref::<String>() {
+            s.clone()
+        } else {
+            "<non-string panic payload>".to_string()
+        };
+        AgentError::Panic(message)
+    }
+}
+
+impl fmt::Display for AgentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AgentError::OutputClosed(ref name) => write!(
+                f,
+                "output {} is closed",
+                name.as_deref().unwrap_or("<unnamed>")
+            ),
+            AgentError::OutputOverflow(ref name) => write!(
+                f,
+                "output {} is full",
+                name.as_deref().unwrap_or("<unnamed>")
+            ),
+            AgentError::Panic(ref message) => write!(f, "handler panicked: {}", message),
+            AgentError::Timeout => write!(f, "deadline passed"),
+            AgentError::Unflushed(ref outputs) => {
+                write!(f, "shut down with undelivered messages on")?;
+                for (name, count) in outputs {
+                    write!(f, " {} ({})", name.as_deref().unwrap_or("<unnamed>"), count)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for AgentError {}
+
+/// Agents built with `Builder::finish` never fail, so their error type is `()`.
+impl From<AgentError> for () {
+    fn from(_: AgentError) {}
+}
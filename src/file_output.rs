@@ -0,0 +1,172 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::sink::Sink;
+
+use crate::ClockHandle;
+
+/// When `FileOutput` starts a new file.
+pub struct RotationOptions {
+    max_bytes: Option<u64>,
+    max_age: Option<Duration>,
+}
+
+impl RotationOptions {
+    /// Never rotates.
+    pub fn new() -> RotationOptions {
+        RotationOptions {
+            max_bytes: None,
+            max_age: None,
+        }
+    }
+
+    /// Starts a new file before one would grow past `max_bytes`. A single
+    /// line longer than that still gets a file of its own.
+    pub fn max_bytes(mut self, max_bytes: u64) -> RotationOptions {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Starts a new file for the first line written once the current one is
+    /// `max_age` old on the output's clock.
+    pub fn max_age(mut self, max_age: Duration) -> RotationOptions {
+        self.max_age = Some(max_age);
+        self
+    }
+}
+
+impl Default for RotationOptions {
+    fn default() -> RotationOptions {
+        RotationOptions::new()
+    }
+}
+
+type EncodeFn<T> = dyn FnMut(&T, &mut Vec<u8>) -> io::Result<()>;
+
+/// Sink that writes every message as a line to a file, for use with
+/// `Builder::new_file_output`.
+///
+/// The files are `path` with a numeric suffix, `events.log.0`, `events.log.1`
+/// and so on, starting at the first one that doesn't exist yet. Writes are
+/// blocking, which suits audit and event logs of moderate volume.
+pub struct FileOutput<T> {
+    path: PathBuf,
+    clock: ClockHandle,
+    options: RotationOptions,
+    encode: Box<EncodeFn<T>>,
+    index: u64,
+    file: BufWriter<File>,
+    written: u64,
+    opened: Instant,
+    line: Vec<u8>,
+    phantom_data: PhantomData<fn(&T)>,
+}
+
+impl<T> FileOutput<T> {
+    /// `encode` appends a message to the buffer it is given, without the line
+    /// break.
+    pub fn new<P, F>(
+        path: P,
+        clock: ClockHandle,
+        options: RotationOptions,
+        encode: F,
+    ) -> io::Result<FileOutput<T>>
+    where
+        P: Into<PathBuf>,
+        F: FnMut(&T, &mut Vec<u8>) -> io::Result<()> + 'static,
+    {
+        let path = path.into();
+        let mut index = 0;
+        while numbered(&path, index).exists() {
+            index += 1;
+        }
+        let file = create(&path, index)?;
+        Ok(FileOutput {
+            path,
+            opened: clock.now(),
+            clock,
+            options,
+            encode: Box::new(encode),
+            index,
+            file,
+            written: 0,
+            line: Vec::new(),
+            phantom_data: PhantomData,
+        })
+    }
+
+    /// The file currently written to.
+    pub fn current_path(&self) -> PathBuf {
+        numbered(&self.path, self.index)
+    }
+
+    fn write(&mut self, value: &T) -> io::Result<()> {
+        self.line.clear();
+        (self.encode)(value, &mut self.line)?;
+        self.line.push(b'\n');
+
+        let len = self.line.len() as u64;
+        let full = self.options.max_bytes.is_some_and(|m| self.written + len > m);
+        let old = self.options.max_age.is_some_and(|a| self.clock.now() >= self.opened + a);
+        if self.written > 0 && (full || old) {
+            self.file.flush()?;
+            self.index += 1;
+            self.file = create(&self.path, self.index)?;
+            self.written = 0;
+            self.opened = self.clock.now();
+        }
+        self.file.write_all(&self.line)?;
+        self.written += len;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<T: serde::Serialize> FileOutput<T> {
+    /// Writes the messages as newline-delimited JSON.
+    pub fn json<P: Into<PathBuf>>(
+        path: P,
+        clock: ClockHandle,
+        options: RotationOptions,
+    ) -> io::Result<FileOutput<T>> {
+        FileOutput::new(path, clock, options, |v: &T, line: &mut Vec<u8>| {
+            Ok(serde_json::to_writer(line, v)?)
+        })
+    }
+}
+
+fn numbered(path: &Path, index: u64) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    name.into()
+}
+
+fn create(path: &Path, index: u64) -> io::Result<BufWriter<File>> {
+    let file = OpenOptions::new().write(true).create_new(true).open(numbered(path, index))?;
+    Ok(BufWriter::new(file))
+}
+
+impl<T> Sink<T> for FileOutput<T> {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, value: T) -> io::Result<()> {
+        self.get_mut().write(&value)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(self.get_mut().file.flush())
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
@@ -0,0 +1,117 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+
+use futures::stream::Stream;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Health {
+    Starting,
+    Healthy,
+    Degraded(String),
+    Failed,
+}
+
+struct HealthState {
+    health: Health,
+    version: u64,
+    // Cleared when the `HealthHandle` is dropped, which ends the streams.
+    open: bool,
+    watchers: Vec<Waker>,
+}
+
+/// Lets the agent state publish its health, obtained from `Builder::new_health`.
+pub struct HealthHandle {
+    state: Rc<RefCell<HealthState>>,
+}
+
+/// Observes the health published through a `HealthHandle`.
+///
+/// As a stream it yields the current health once and then every change, and
+/// ends once the `HealthHandle` is dropped along with the agent.
+pub struct HealthWatch {
+    state: Rc<RefCell<HealthState>>,
+    seen: Option<u64>,
+}
+
+impl HealthHandle {
+    pub(crate) fn new() -> HealthHandle {
+        HealthHandle {
+            state: Rc::new(RefCell::new(HealthState {
+                health: Health::Starting,
+                version: 0,
+                open: true,
+                watchers: Vec::new(),
+            })),
+        }
+    }
+
+    pub fn set(&mut self, health: Health) {
+        let mut s = self.state.borrow_mut();
+        if s.health == health {
+            return;
+        }
+        s.health = health;
+        s.version += 1;
+        for w in s.watchers.drain(..) {
+            w.wake();
+        }
+    }
+
+    pub fn get(&self) -> Health {
+        self.state.borrow().health.clone()
+    }
+
+    pub fn watch(&self) -> HealthWatch {
+        HealthWatch {
+            state: self.state.clone(),
+            seen: None,
+        }
+    }
+}
+
+impl Drop for HealthHandle {
+    fn drop(&mut self) {
+        let mut s = self.state.borrow_mut();
+        s.open = false;
+        for w in s.watchers.drain(..) {
+            w.wake();
+        }
+    }
+}
+
+impl HealthWatch {
+    pub fn get(&self) -> Health {
+        self.state.borrow().health.clone()
+    }
+}
+
+impl Clone for HealthWatch {
+    fn clone(&self) -> HealthWatch {
+        HealthWatch {
+            state: self.state.clone(),
+            seen: None,
+        }
+    }
+}
+
+impl Stream for HealthWatch {
+    type Item = Health;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let state = self.state.clone();
+        let mut s = state.borrow_mut();
+        if self.seen == Some(s.version) {
+            if !s.open {
+                return Poll::Ready(None);
+            }
+            if !s.watchers.iter().any(|w| w.will_wake(cx.waker())) {
+                s.watchers.push(cx.waker().clone());
+            }
+            return Poll::Pending;
+        }
+        self.seen = Some(s.version);
+        Poll::Ready(Some(s.health.clone()))
+    }
+}
@@ -1,35 +1,82 @@
-extern crate futures;
-
+mod address;
+mod agent_set;
+mod anti_entropy;
+mod assert_agent;
+mod compare_agent;
+mod duplex;
+mod error;
+mod file_output;
+mod health;
+mod ordering_oracle;
+mod quorum;
+mod registry;
+mod request;
+mod session_router;
+mod shared_view;
+mod shutdown;
+mod supervisor;
 mod timer;
+mod watermark;
 
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::collections::VecDeque;
+use std::future::Future;
 use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::task::{Context, Poll};
 use std::time::{Duration, Instant};
 
-use futures::{Async, AsyncSink, Poll};
-use futures::future::Future;
-use futures::sink::Sink;
-use futures::stream::Stream;
-use futures::sync::mpsc::{Receiver, Sender};
-use futures::task::current;
+use futures::channel::mpsc::{unbounded, Receiver, Sender};
+use futures::future;
+use futures::sink::{Sink, SinkExt};
+use futures::stream::{Stream, StreamExt};
+use futures::task::noop_waker_ref;
+use tokio::task::{JoinHandle, LocalSet};
+use tokio_util::sync::PollSender;
+use tokio_util::task::LocalPoolHandle;
+
+pub use crate::address::Address;
+pub use crate::agent_set::AgentSet;
+pub use crate::anti_entropy::{AntiEntropyAgent, SyncMessage};
+pub use crate::assert_agent::AssertAgent;
+pub use crate::compare_agent::{CompareAgent, Difference};
+pub use crate::duplex::{duplex, LinkEnd};
+pub use crate::error::AgentError;
+pub use crate::file_output::{FileOutput, RotationOptions};
+pub use crate::health::{Health, HealthHandle, HealthWatch};
+pub use crate::ordering_oracle::{OrderingOracle, Recorded};
+pub use crate::quorum::{QuorumBroadcast, QuorumOptions};
+pub use crate::registry::AgentRegistry;
+pub use crate::request::{Request, Responder};
+pub use crate::session_router::SessionRouter;
+pub use crate::shared_view::{SharedView, ViewPublisher};
+pub use crate::shutdown::ShutdownHandle;
+pub use crate::supervisor::{RestartStrategy, Supervisor};
+pub use crate::timer::{Clock, ClockHandle, MockClock, SystemClock};
+pub use crate::watermark::Watermarks;
 
-pub use timer::{ClockHandle, MockClock};
+use crate::watermark::WatermarkTimer;
 
 enum InputResult {
     Ready,
+    Handled,
     Closed,
 }
 
 enum TimerResult {
     Ready,
+    Handled,
     Closed,
 }
 
 enum OutputResult {
     Ready,
     NotReady,
+    Failed,
+    Overflowed,
     Closed,
 }
 
@@ -38,16 +85,32 @@ pub enum TimerRun {
     Stop,
 }
 
+type BoxedStream<T> = Pin<Box<dyn Stream<Item = T>>>;
+type BoxedSink<T> = Pin<Box<dyn Sink<T, Error = ()>>>;
+
 trait PollableInput<S> {
-    fn poll(&mut self, &mut S) -> InputResult;
+    fn poll(&mut self, state: &mut S, cx: &mut Context) -> InputResult;
+    fn priority(&self) -> u8;
 }
 
 trait PollableOutput {
-    fn poll(&mut self) -> OutputResult;
+    fn poll(&mut self, cx: &mut Context) -> OutputResult;
+    fn name(&self) -> Option<String>;
+    // Whether every message sent so far has been handed to the sink, or can
+    // no longer be.
+    fn is_drained(&self) -> bool;
+    // Number of buffered messages not yet handed to the sink.
+    fn pending(&self) -> usize;
+    fn is_best_effort(&self) -> bool;
+    // Whether the handle is gone and nothing is left to deliver, so the agent
+    // can drop the output.
+    fn is_abandoned(&self) -> bool;
 }
 
 trait PollableTimer<S> {
-    fn poll(&mut self, &mut S) -> TimerResult;
+    fn poll(&mut self, state: &mut S, cx: &mut Context) -> TimerResult;
+    // Applies changes made through the timer's handle since the last poll.
+    fn rearm(&mut self, cx: &mut Context);
 }
 
 struct Input<S, T, I, E>
@@ -55,7 +118,13 @@ where
     for<'r> I: FnMut(&'r mut S, T),
     for<'r> E: FnMut(&'r mut S),
 {
-    receiver: Option<Receiver<T>>,
+    #[cfg_attr(not(feature = "tracing"), allow(dead_code))]
+    name: Option<String>,
+    receiver: Option<BoxedStream<T>>,
+    // A passive input doesn't keep the agent running once it has nothing
+    // queued.
+    passive: bool,
+    priority: u8,
     on_item: I,
     on_end: E,
     phantom_data: PhantomData<S>,
@@ -66,72 +135,700 @@ where
     for<'r> I: std::ops::FnMut(&'r mut S, T),
     for<'r> E: std::ops::FnMut(&'r mut S),
 {
-    fn poll(&mut self, state: &mut S) -> InputResult {
-        if let Some(ref mut r) = self.receiver {
-            match r.poll() {
-                Ok(Async::Ready(Some(v))) => (self.on_item)(state, v),
-                Ok(Async::Ready(None)) => (self.on_end)(state),
-                Ok(Async::NotReady) => (),
-                Err(_) => (),
+    fn poll(&mut self, state: &mut S, cx: &mut Context) -> InputResult {
+        if let Some(r) = self.receiver.as_mut() {
+            #[cfg(feature = "tracing")]
+            let _span = tracing::trace_span!("input", name = port_name(&self.name)).entered();
+            match r.as_mut().poll_next(cx) {
+                Poll::Ready(Some(v)) => {
+                    (self.on_item)(state, v);
+                    // Only one item is taken per poll and the receiver will not
+                    // wake us for the ones already queued, so ask to be polled
+                    // again.
+                    cx.waker().wake_by_ref();
+                    return InputResult::Handled;
+                }
+                Poll::Ready(None) => {
+                    self.receiver = None;
+                    (self.on_end)(state);
+                    // Report the input as closed on the next poll.
+                    cx.waker().wake_by_ref();
+                    return InputResult::Handled;
+                }
+                Poll::Pending if self.passive => return InputResult::Closed,
+                Poll::Pending => (),
             }
             return InputResult::Ready;
         }
         InputResult::Closed
     }
+
+    fn priority(&self) -> u8 {
+        self.priority
+    }
+}
+
+// Shared by all outputs of an agent to order sends across them. A message
+// counts as delivered once its sink has completed the send (or failed it).
+//
+// In ordered mode `order` holds the id of the output for every undelivered
+// message, in the order the messages were sent; only the output at the front
+// may start its next send. Independently, barriers split the messages into
+// epochs and `epochs` counts the undelivered messages of each open epoch,
+// oldest first; only messages of the oldest open epoch may be started.
+struct Sequencer {
+    ordered: bool,
+    order: VecDeque<usize>,
+    first_epoch: u64,
+    epochs: VecDeque<usize>,
+    delivered: u64,
+    // Number of outputs handed an id so far.
+    outputs: usize,
+}
+
+impl Sequencer {
+    fn new() -> Sequencer {
+        let mut epochs = VecDeque::new();
+        epochs.push_back(0);
+        Sequencer {
+            ordered: false,
+            order: VecDeque::new(),
+            first_epoch: 0,
+            epochs,
+            delivered: 0,
+            outputs: 0,
+        }
+    }
+
+    fn new_id(&mut self) -> usize {
+        self.outputs += 1;
+        self.outputs - 1
+    }
+
+    // Registers a message sent to output `id` and returns its epoch.
+    fn push(&mut self, id: usize) -> u64 {
+        if self.ordered {
+            self.order.push_back(id);
+        }
+        *self.epochs.back_mut().unwrap() += 1;
+        self.first_epoch + self.epochs.len() as u64 - 1
+    }
+
+    fn may_start(&self, id: usize, epoch: u64) -> bool {
+        (!self.ordered || self.order.front() == Some(&id)) && epoch == self.first_epoch
+    }
+
+    // Settles a message of output `id`, which comes after `skip` of the
+    // output's messages still waiting, e.g. one in flight when a buffered
+    // message is dropped. It need not be at the front of `order`.
+    fn delivered(&mut self, id: usize, skip: usize, epoch: u64) {
+        if self.ordered {
+            let at = self.order.iter().enumerate().filter(|&(_, &o)| o == id).nth(skip);
+            if let Some((at, _)) = at {
+                self.order.remove(at);
+            }
+        }
+        self.epochs[(epoch - self.first_epoch) as usize] -= 1;
+        while self.epochs.len() > 1 && self.epochs[0] == 0 {
+            self.epochs.pop_front();
+            self.first_epoch += 1;
+        }
+        self.delivered += 1;
+    }
+
+    fn barrier(&mut self) {
+        if *self.epochs.back().unwrap() > 0 {
+            self.epochs.push_back(0);
+        }
+    }
+
+    // Whether a delivery on one output can unblock another one.
+    fn is_blocking(&self) -> bool {
+        self.ordered || self.epochs.len() > 1
+    }
+}
+
+// An output's place in the agent's sequencer. Mirrors have none, as they
+// take no part in ordering.
+struct SequencerSlot {
+    id: usize,
+    sequencer: Option<Rc<RefCell<Sequencer>>>,
+}
+
+impl SequencerSlot {
+    fn push(&self) -> u64 {
+        match self.sequencer {
+            Some(ref q) => q.borrow_mut().push(self.id),
+            None => 0,
+        }
+    }
+
+    fn may_start(&self, epoch: u64) -> bool {
+        match self.sequencer {
+            Some(ref q) => q.borrow().may_start(self.id, epoch),
+            None => true,
+        }
+    }
+
+    fn delivered(&self, epoch: u64) {
+        if let Some(ref q) = self.sequencer {
+            q.borrow_mut().delivered(self.id, 0, epoch);
+        }
+    }
+
+    // For a message dropped from the buffer while `skip` messages of the
+    // output are ahead of it.
+    fn dropped(&self, epoch: u64, skip: usize) {
+        if let Some(ref q) = self.sequencer {
+            q.borrow_mut().delivered(self.id, skip, epoch);
+        }
+    }
 }
 
 struct OutputState<T> {
-    sender: Option<Sender<T>>,
-    send_in_progress: bool,
-    buffer: VecDeque<T>,
+    slot: SequencerSlot,
+    sender: Option<BoxedSink<T>>,
+    // Epoch of the message whose send is in progress.
+    in_flight: Option<u64>,
+    // Set once the sink has rejected a message.
+    failed: bool,
+    // Set by `Output::close`: close the sink once the buffer is flushed.
+    closing: bool,
+    deferred: bool,
+    best_effort: bool,
+    backpressure: BackpressurePolicy,
+    // Set once a message has been refused under `BackpressurePolicy::FailSend`.
+    overflowed: bool,
+    buffer: VecDeque<(u64, T)>,
+    // Messages that could not be delivered, kept for the output's error
+    // handler if it has one. Such outputs never fail the agent.
+    rejected: Option<Vec<T>>,
+    // Messages dropped by the backpressure policy, kept for the output's
+    // overflow handler if it has one.
+    overflow: Option<Vec<T>>,
 }
 
 impl<T> OutputState<T> {
-    fn poll(&mut self) -> OutputResult {
-        if let Some(ref mut s) = self.sender {
-            if self.send_in_progress {
-                // Try to finish the current send.
-                match s.poll_complete() {
-                    Ok(Async::Ready(_)) => self.send_in_progress = false,
-                    Ok(Async::NotReady) => return OutputResult::NotReady,
-                    Err(_) => self.send_in_progress = false,
+    // Outputs without a sequencer take no part in ordering, like mirrors.
+    fn new(
+        sequencer: Option<&Rc<RefCell<Sequencer>>>,
+        sender: BoxedSink<T>,
+        options: &OutputOptions,
+    ) -> OutputState<T> {
+        OutputState {
+            slot: SequencerSlot {
+                id: sequencer.map_or(0, |q| q.borrow_mut().new_id()),
+                sequencer: sequencer.cloned(),
+            },
+            sender: Some(sender),
+            in_flight: None,
+            failed: false,
+            closing: false,
+            deferred: options.deferred,
+            best_effort: options.best_effort,
+            backpressure: options.backpressure,
+            overflowed: false,
+            buffer: VecDeque::new(),
+            rejected: None,
+            overflow: None,
+        }
+    }
+
+    fn push(&mut self, value: T) {
+        if self.sender.is_none() || self.closing {
+            self.reject(value);
+            return;
+        }
+        match self.backpressure {
+            BackpressurePolicy::Unbounded => (),
+            BackpressurePolicy::DropOldest(n) => {
+                if self.buffer.len() >= n {
+                    match self.buffer.pop_front() {
+                        Some((epoch, oldest)) => {
+                            self.slot.dropped(epoch, self.in_flight.is_some() as usize);
+                            self.shed(oldest);
+                        }
+                        None => return self.shed(value),
+                    }
+                }
+            }
+            BackpressurePolicy::DropNewest(n) => {
+                if self.buffer.len() >= n {
+                    return self.shed(value);
+                }
+            }
+            BackpressurePolicy::FailSend(n) => {
+                if self.buffer.len() >= n {
+                    self.overflowed = true;
+                    return self.shed(value);
                 }
             }
+        }
+        let epoch = self.slot.push();
+        self.buffer.push_back((epoch, value));
+        if !self.deferred {
+            // Handlers have no context to register with, but the agent polls
+            // its outputs again after every handler, with the real one.
+            self.poll(&mut Context::from_waker(noop_waker_ref()));
+        }
+    }
+
+    fn is_drained(&self) -> bool {
+        self.sender.is_none() || (self.buffer.is_empty() && self.in_flight.is_none())
+    }
+
+    fn pending(&self) -> usize {
+        match self.sender {
+            Some(_) => self.buffer.len(),
+            None => 0,
+        }
+    }
+
+    // The buffer is full, so the message is dropped, or handed to the
+    // output's overflow handler if it has one.
+    fn shed(&mut self, value: T) {
+        if let Some(ref mut o) = self.overflow {
+            o.push(value);
+        }
+    }
+
+    // The message can't be delivered. It is lost unless the output has an
+    // error handler to take it.
+    fn reject(&mut self, value: T) {
+        self.failed = true;
+        if let Some(ref mut r) = self.rejected {
+            r.push(value);
+        }
+    }
+
+    // Drops a sink that has failed, since it can't be polled again, along with
+    // the messages still waiting for it.
+    fn close(&mut self) {
+        self.failed = true;
+        self.sender = None;
+        let skip = self.in_flight.is_some() as usize;
+        while let Some((epoch, v)) = self.buffer.pop_front() {
+            self.slot.dropped(epoch, skip);
+            self.reject(v);
+        }
+    }
+
+    fn poll(&mut self, cx: &mut Context) -> OutputResult {
+        if let Some(ref mut s) = self.sender {
+            loop {
+                if let Some(epoch) = self.in_flight {
+                    // Try to finish the current send.
+                    match s.as_mut().poll_flush(cx) {
+                        Poll::Ready(Ok(())) => {
+                            self.in_flight = None;
+                            self.slot.delivered(epoch);
+                        }
+                        Poll::Pending => return OutputResult::NotReady,
+                        Poll::Ready(Err(_)) => {
+                            self.in_flight = None;
+                            self.slot.delivered(epoch);
+                            break;
+                        }
+                    }
+                }
 
-            if !self.send_in_progress {
                 // Initiate new send.
-                match self.buffer.pop_front() {
-                    Some(v) => {
-                        match s.start_send(v) {
-                            Ok(AsyncSink::Ready) => self.send_in_progress = true,
-                            Ok(AsyncSink::NotReady(v)) => self.buffer.push_front(v),
-                            Err(_) => (),
+                match self.buffer.front() {
+                    Some(&(epoch, _)) => {
+                        if !self.slot.may_start(epoch) {
+                            // Another output holds an earlier message.
+                            return OutputResult::Ready;
+                        }
+                        match s.as_mut().poll_ready(cx) {
+                            Poll::Ready(Ok(())) => {
+                                let (epoch, v) = self.buffer.pop_front().unwrap();
+                                match s.as_mut().start_send(v) {
+                                    Ok(()) => self.in_flight = Some(epoch),
+                                    Err(_) => {
+                                        self.slot.delivered(epoch);
+                                        break;
+                                    }
+                                }
+                            }
+                            Poll::Pending => return OutputResult::Ready,
+                            Poll::Ready(Err(_)) => break,
                         }
                     }
-                    None => (),
+                    None if self.closing => match s.as_mut().poll_close(cx) {
+                        Poll::Ready(_) => {
+                            self.sender = None;
+                            return OutputResult::Closed;
+                        }
+                        Poll::Pending => return OutputResult::NotReady,
+                    },
+                    None => return OutputResult::Ready,
                 }
             }
-            return OutputResult::Ready;
+            self.close();
         }
         OutputResult::Closed
     }
 }
 
+// Stream over a Tokio channel, which only offers `poll_recv`.
+struct TokioReceiver<T>(tokio::sync::mpsc::Receiver<T>);
+
+impl<T> Stream for TokioReceiver<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.0.poll_recv(cx)
+    }
+}
+
+fn port_name(name: &Option<String>) -> &str {
+    name.as_deref().unwrap_or("<unnamed>")
+}
+
+/// Options for `Builder::new_input_with_options`.
+pub struct InputOptions {
+    name: Option<String>,
+    passive: bool,
+    priority: u8,
+}
+
+impl InputOptions {
+    pub fn new() -> InputOptions {
+        InputOptions {
+            name: None,
+            passive: false,
+            priority: 0,
+        }
+    }
+
+    /// Names the input in tracing spans and error messages.
+    pub fn name(mut self, name: &str) -> InputOptions {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Inputs of a higher priority are drained before the agent takes an item
+    /// from one of a lower priority, e.g. to keep control messages from
+    /// queueing up behind bulk data. Inputs of the same priority are polled in
+    /// the order they were added. 0 by default.
+    pub fn priority(mut self, priority: u8) -> InputOptions {
+        self.priority = priority;
+        self
+    }
+}
+
+impl Default for InputOptions {
+    fn default() -> InputOptions {
+        InputOptions::new()
+    }
+}
+
+/// When `Builder::new_acked_input` commits the tokens it has collected.
+pub struct CommitOptions {
+    batch: usize,
+    clock: ClockHandle,
+    interval: Duration,
+}
+
+impl CommitOptions {
+    /// Commits every `interval` of `clock` time.
+    pub fn new(clock: ClockHandle, interval: Duration) -> CommitOptions {
+        CommitOptions {
+            batch: 100,
+            clock,
+            interval,
+        }
+    }
+
+    /// Also commits as soon as `batch` tokens have piled up. 100 by default.
+    pub fn batch(mut self, batch: usize) -> CommitOptions {
+        assert!(batch > 0, "batch size must be positive");
+        self.batch = batch;
+        self
+    }
+}
+
+/// What an output does with messages its sink can't keep up with, see
+/// `OutputOptions::backpressure`. Limits count the messages buffered in the
+/// output, not the one being sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackpressurePolicy {
+    /// Buffer everything.
+    Unbounded,
+    /// Keep at most `n` messages, dropping the oldest one to make room.
+    DropOldest(usize),
+    /// Keep at most `n` messages, dropping any message sent on top.
+    DropNewest(usize),
+    /// Keep at most `n` messages. Sending another one fails an agent built
+    /// with `Builder::finish_v2` and is dropped otherwise.
+    FailSend(usize),
+}
+
+/// Options for `Builder::new_output_with_options`.
+pub struct OutputOptions {
+    name: Option<String>,
+    deferred: bool,
+    best_effort: bool,
+    backpressure: BackpressurePolicy,
+}
+
+impl OutputOptions {
+    pub fn new() -> OutputOptions {
+        OutputOptions {
+            name: None,
+            deferred: false,
+            best_effort: false,
+            backpressure: BackpressurePolicy::Unbounded,
+        }
+    }
+
+    /// Names the output in tracing spans and error messages.
+    pub fn name(mut self, name: &str) -> OutputOptions {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Only buffer messages in `Output::send` and leave all sink interaction
+    /// to the agent's output phase, which runs after every handler. Messages
+    /// are still delivered in the order they were sent.
+    pub fn deferred(mut self) -> OutputOptions {
+        self.deferred = true;
+        self
+    }
+
+    /// When the agent is shut down, flush this output only once every output
+    /// not marked best-effort is drained. It can still be cut short by the
+    /// deadline from `Builder::set_shutdown_timeout`.
+    pub fn best_effort(mut self) -> OutputOptions {
+        self.best_effort = true;
+        self
+    }
+
+    /// Bounds the output's buffer. Unbounded by default.
+    pub fn backpressure(mut self, policy: BackpressurePolicy) -> OutputOptions {
+        self.backpressure = policy;
+        self
+    }
+}
+
+impl Default for OutputOptions {
+    fn default() -> OutputOptions {
+        OutputOptions::new()
+    }
+}
+
 pub struct Output<T> {
+    name: Rc<Option<String>>,
+    state: Rc<RefCell<OutputState<T>>>,
+    mirror: Option<Mirror<T>>,
+}
+
+// Secondary sink that receives a copy of every message sent to an output.
+// Its backpressure and failures never affect the agent.
+struct Mirror<T> {
     state: Rc<RefCell<OutputState<T>>>,
+    clone: fn(&T) -> T,
+}
+
+impl<T> Mirror<T> {
+    fn share(&self) -> Mirror<T> {
+        Mirror {
+            state: self.state.clone(),
+            clone: self.clone,
+        }
+    }
+}
+
+impl<T> PollableOutput for Mirror<T> {
+    fn poll(&mut self, cx: &mut Context) -> OutputResult {
+        self.state.borrow_mut().poll(cx);
+        OutputResult::Ready
+    }
+
+    fn name(&self) -> Option<String> {
+        None
+    }
+
+    // Mirrors are best-effort, so they never hold up the agent.
+    fn is_drained(&self) -> bool {
+        true
+    }
+
+    fn pending(&self) -> usize {
+        0
+    }
+
+    fn is_best_effort(&self) -> bool {
+        true
+    }
+
+    fn is_abandoned(&self) -> bool {
+        Rc::strong_count(&self.state) == 1 && self.state.borrow().is_drained()
+    }
 }
 
 impl<T> Output<T> {
     pub fn send(&mut self, value: T) {
-        let mut s = self.state.borrow_mut();
-        s.buffer.push_back(value);
-        s.poll();
+        // The output state is only borrowed for the duration of a single push
+        // and flush, so failing to borrow here means `send` was re-entered from
+        // inside the output's own poll. Report that instead of the opaque
+        // `BorrowMutError`.
+        let mut s = match self.state.try_borrow_mut() {
+            Ok(s) => s,
+            Err(_) => panic!(
+                "Output::send called re-entrantly while output {} is being polled",
+                port_name(&self.name)
+            ),
+        };
+        if let Some(ref m) = self.mirror {
+            m.state.borrow_mut().push((m.clone)(&value));
+        }
+        s.push(value);
+    }
+
+    /// Ends the stream: the sink is closed once the messages sent so far are
+    /// flushed to it, so the receiving end sees the end of the stream after
+    /// the last of them. Messages sent afterwards are rejected.
+    pub fn close(&mut self) {
+        let mut s = match self.state.try_borrow_mut() {
+            Ok(s) => s,
+            Err(_) => panic!(
+                "Output::close called re-entrantly while output {} is being polled",
+                port_name(&self.name)
+            ),
+        };
+        s.closing = true;
+        if !s.deferred {
+            s.poll(&mut Context::from_waker(noop_waker_ref()));
+        }
+    }
+}
+
+/// Agent-wide barrier across all outputs, obtained from `Builder::new_barrier`.
+pub struct Barrier {
+    sequencer: Rc<RefCell<Sequencer>>,
+}
+
+impl Barrier {
+    /// Every message sent before this call, to any output of the agent, is
+    /// flushed to its sink before any message sent after it is started.
+    pub fn insert(&mut self) {
+        self.sequencer.borrow_mut().barrier();
+    }
+}
+
+/// Creates outputs for a running agent, obtained from
+/// `Builder::output_factory`.
+///
+/// The agent picks up a new output on its next output phase and from then on
+/// treats it like the ones created by the `Builder`: it takes part in ordering
+/// and barriers and the agent waits for it to drain before finishing. Once an
+/// output's handle is dropped and its messages are handed to the sink, the
+/// agent drops the output along with the sink.
+#[derive(Clone)]
+pub struct OutputFactory {
+    sequencer: Rc<RefCell<Sequencer>>,
+    added: Rc<RefCell<Vec<Box<dyn PollableOutput>>>>,
+}
+
+impl OutputFactory {
+    pub fn new_output<T: 'static>(&self, sender: Sender<T>) -> Output<T> {
+        self.new_output_with_options(sender, OutputOptions::new())
+    }
+
+    pub fn new_output_with_options<T: 'static>(
+        &self,
+        sender: Sender<T>,
+        options: OutputOptions,
+    ) -> Output<T> {
+        let sink = Box::pin(sender.sink_map_err(drop));
+        let state = Rc::new(RefCell::new(OutputState::new(Some(&self.sequencer), sink, &options)));
+        let name = Rc::new(options.name);
+        self.added.borrow_mut().push(Box::new(Output {
+            name: name.clone(),
+            state: state.clone(),
+            mirror: None,
+        }));
+        Output {
+            name,
+            state,
+            mirror: None,
+        }
     }
 }
 
 impl<T> PollableOutput for Output<T> {
-    fn poll(&mut self) -> OutputResult {
-        self.state.borrow_mut().poll()
+    fn poll(&mut self, cx: &mut Context) -> OutputResult {
+        match self.state.try_borrow_mut() {
+            Ok(mut s) => match s.poll(cx) {
+                _ if s.failed && s.rejected.is_none() => OutputResult::Failed,
+                _ if s.overflowed => OutputResult::Overflowed,
+                r => r,
+            },
+            Err(_) => panic!(
+                "Output {} polled re-entrantly while a send is in progress",
+                port_name(&self.name)
+            ),
+        }
+    }
+
+    fn name(&self) -> Option<String> {
+        (*self.name).clone()
+    }
+
+    fn is_drained(&self) -> bool {
+        self.state.borrow().is_drained()
+    }
+
+    fn pending(&self) -> usize {
+        self.state.borrow().pending()
+    }
+
+    fn is_best_effort(&self) -> bool {
+        self.state.borrow().best_effort
+    }
+
+    fn is_abandoned(&self) -> bool {
+        Rc::strong_count(&self.state) == 1 && self.is_drained()
+    }
+}
+
+// Timer settings shared between a timer and its `TimerHandle`. `dirty` asks
+// the timer to re-arm from the current time on its next poll.
+struct TimerControl {
+    on: bool,
+    period: Duration,
+    dirty: bool,
+}
+
+/// Controls a timer from the agent state, obtained from `Builder::new_timer`.
+pub struct TimerHandle {
+    control: Rc<RefCell<TimerControl>>,
+}
+
+impl TimerHandle {
+    /// (Re)starts the timer so that it next fires one period from now.
+    pub fn start(&mut self) {
+        let mut c = self.control.borrow_mut();
+        c.on = true;
+        c.dirty = true;
+    }
+
+    /// Stops the timer until `start` or `reschedule` is called.
+    pub fn cancel(&mut self) {
+        self.control.borrow_mut().on = false;
+    }
+
+    /// Changes the period and restarts the timer from now.
+    pub fn reschedule(&mut self, period: Duration) {
+        let mut c = self.control.borrow_mut();
+        c.period = period;
+        c.on = true;
+        c.dirty = true;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.control.borrow().on
     }
 }
 
@@ -139,83 +836,760 @@ struct Timer<S, F>
 where
     for<'r> F: FnMut(&'r mut S) -> TimerRun,
 {
+    #[cfg_attr(not(feature = "tracing"), allow(dead_code))]
+    name: Option<String>,
     clock: ClockHandle,
     on_timer: F,
-    on: bool,
-    period: Duration,
+    control: Rc<RefCell<TimerControl>>,
     next_activation: Option<Instant>,
     phantom_data: PhantomData<S>,
 }
 
+impl<S, F> Timer<S, F>
+where
+    for<'r> F: FnMut(&'r mut S) -> TimerRun,
+{
+    fn arm(&mut self, next: Instant, cx: &mut Context) {
+        self.next_activation = Some(next);
+        self.clock.add_activation(cx.waker().clone(), next);
+    }
+}
+
 impl<S, F> PollableTimer<S> for Timer<S, F>
 where
     for<'r> F: FnMut(&'r mut S) -> TimerRun,
 {
-    fn poll(&mut self, state: &mut S) -> TimerResult {
-        if !self.on {
+    fn poll(&mut self, state: &mut S, cx: &mut Context) -> TimerResult {
+        if !self.control.borrow().on {
             return TimerResult::Closed;
         }
+        self.rearm(cx);
 
         let now = self.clock.now();
+        let period = self.control.borrow().period;
         match self.next_activation {
-            None => {
-                let next = now + self.period;
-                self.next_activation = Some(next);
-                self.clock.add_activation(current(), next);
-            }
+            None => self.arm(now + period, cx),
             Some(mut next) => {
                 if now >= next {
-                    (self.on_timer)(state);
-                    while now >= next {
-                        next = next + self.period
+                    #[cfg(feature = "tracing")]
+                    let _span = tracing::trace_span!("timer", name = port_name(&self.name)).entered();
+                    // The control is not borrowed here, so the handler may use
+                    // the timer's own handle.
+                    if let TimerRun::Stop = (self.on_timer)(state) {
+                        self.control.borrow_mut().on = false;
+                        // Report the timer as closed on the next poll rather
+                        // than at an activation that is never going to come.
+                        cx.waker().wake_by_ref();
+                    } else if !self.control.borrow().dirty {
+                        while now >= next {
+                            next += period
+                        }
+                        self.arm(next, cx);
                     }
-                    self.next_activation = Some(next);
-                    self.clock.add_activation(current(), next);
+                    return TimerResult::Handled;
                 }
             }
         }
 
         TimerResult::Ready
     }
+
+    fn rearm(&mut self, cx: &mut Context) {
+        let period = {
+            let mut c = self.control.borrow_mut();
+            if !c.on || !c.dirty {
+                return;
+            }
+            c.dirty = false;
+            c.period
+        };
+        let now = self.clock.now();
+        self.arm(now + period, cx);
+    }
+}
+
+/// Options for `Builder::new_timer_with_options`.
+pub struct TimerOptions {
+    name: Option<String>,
+}
+
+impl TimerOptions {
+    pub fn new() -> TimerOptions {
+        TimerOptions { name: None }
+    }
+
+    /// Names the timer in tracing spans and error messages.
+    pub fn name(mut self, name: &str) -> TimerOptions {
+        self.name = Some(name.to_string());
+        self
+    }
+}
+
+impl Default for TimerOptions {
+    fn default() -> TimerOptions {
+        TimerOptions::new()
+    }
+}
+
+type ExceededFn<S> = dyn FnMut(&mut S, Duration);
+type StopFn<S> = dyn FnOnce(&mut S);
+type FailFn<S, E> = dyn FnMut(&mut S) -> Option<E>;
+type RejectedFn<S> = dyn FnMut(&mut S) -> bool;
+type ErrorFn<S, T> = dyn FnMut(&mut S, T);
+
+// Reports handler invocations that took longer than `limit` of real time.
+struct Watchdog<S> {
+    limit: Duration,
+    on_exceeded: Box<ExceededFn<S>>,
+}
+
+impl<S> Watchdog<S> {
+    fn check(&mut self, state: &mut S, started: Instant) {
+        let elapsed = started.elapsed();
+        if elapsed > self.limit {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(?elapsed, limit = ?self.limit, "handler exceeded its time limit");
+            (self.on_exceeded)(state, elapsed);
+        }
+    }
+}
+
+// Caps the number of handler invocations per window of clock time.
+struct Quota {
+    clock: ClockHandle,
+    limit: u32,
+    window: Duration,
+    window_start: Option<Instant>,
+    used: u32,
+    wake_scheduled: bool,
+}
+
+impl Quota {
+    // Returns false once the current window is used up, making sure the task
+    // is woken when the next window starts.
+    fn available(&mut self, cx: &mut Context) -> bool {
+        let now = self.clock.now();
+        let start = *self.window_start.get_or_insert(now);
+        if now >= start + self.window {
+            self.window_start = Some(now);
+            self.used = 0;
+            self.wake_scheduled = false;
+        }
+        if self.used < self.limit {
+            return true;
+        }
+        if !self.wake_scheduled {
+            self.clock.add_activation(cx.waker().clone(), start + self.window);
+            self.wake_scheduled = true;
+        }
+        false
+    }
 }
 
 pub struct Builder<S> {
-    inputs: Vec<Box<PollableInput<S>>>,
-    outputs: Vec<Box<PollableOutput>>,
-    timers: Vec<Box<PollableTimer<S>>>,
+    name: Option<String>,
+    sequencer: Rc<RefCell<Sequencer>>,
+    watchdog: Option<Watchdog<S>>,
+    quota: Option<Quota>,
+    health: Option<HealthWatch>,
+    shutdown_timeout: Option<(ClockHandle, Duration)>,
+    fair_inputs: Option<usize>,
+    poll_budget: Option<usize>,
+    end_with_inputs: bool,
+    on_stop: Option<Box<StopFn<S>>>,
+    on_rejected: Vec<Box<RejectedFn<S>>>,
+    inputs: Vec<Box<dyn PollableInput<S>>>,
+    outputs: Vec<Box<dyn PollableOutput>>,
+    added_outputs: Rc<RefCell<Vec<Box<dyn PollableOutput>>>>,
+    timers: Vec<Box<dyn PollableTimer<S>>>,
 }
 
 impl<S: 'static> Builder<S> {
     pub fn new() -> Builder<S> {
         Builder {
+            name: None,
+            sequencer: Rc::new(RefCell::new(Sequencer::new())),
+            watchdog: None,
+            quota: None,
+            health: None,
+            shutdown_timeout: None,
+            fair_inputs: None,
+            poll_budget: None,
+            end_with_inputs: false,
+            on_stop: None,
+            on_rejected: Vec::new(),
             inputs: Vec::new(),
             outputs: Vec::new(),
+            added_outputs: Rc::new(RefCell::new(Vec::new())),
             timers: Vec::new(),
         }
     }
 
-    pub fn new_input<T: 'static, I: FnMut(&mut S, T) + 'static, E: FnMut(&mut S) + 'static>(
+    /// Names the agent. The name is attached to the agent's poll spans when
+    /// the `tracing` feature is enabled.
+    pub fn set_name(&mut self, name: &str) {
+        self.name = Some(name.to_string());
+    }
+
+    /// When set, messages sent to different outputs of the agent are handed to
+    /// their sinks in the order they were sent, across all outputs. An output
+    /// whose sink is full holds back every output that was sent to later.
+    pub fn set_ordered_outputs(&mut self, ordered: bool) {
+        self.sequencer.borrow_mut().ordered = ordered;
+    }
+
+    /// Measures every input and timer handler invocation against the real
+    /// clock and calls `on_exceeded` with the elapsed time after one that ran
+    /// longer than `limit`. Handlers cannot be preempted, so this only reports.
+    pub fn set_handler_timeout<F: FnMut(&mut S, Duration) + 'static>(
+        &mut self,
+        limit: Duration,
+        on_exceeded: F,
+    ) {
+        self.watchdog = Some(Watchdog {
+            limit,
+            on_exceeded: Box::new(on_exceeded),
+        });
+    }
+
+    /// Once the agent is asked to shut down, waits at most `timeout` of clock
+    /// time for its outputs to drain. An agent built with `finish_v2` then
+    /// fails with `AgentError::Unflushed`, listing what was left behind.
+    pub fn set_shutdown_timeout(&mut self, clock: ClockHandle, timeout: Duration) {
+        self.shutdown_timeout = Some((clock, timeout));
+    }
+
+    /// Polls the inputs round-robin: every poll starts with the input after
+    /// the one that went first last time, so a busy input added early doesn't
+    /// always get served first. Each input may hand up to `items` queued
+    /// messages to its handler per poll instead of one. Priorities still
+    /// apply; the rotation is among inputs of the same priority.
+    pub fn set_fair_inputs(&mut self, items: usize) {
+        assert!(items > 0, "fair inputs must take at least one item");
+        self.fair_inputs = Some(items);
+    }
+
+    /// Hands at most `budget` input messages to handlers per poll. The agent
+    /// then yields to the executor and picks up where it left off on its next
+    /// poll, starting with the input after the last one it served, so a busy
+    /// agent doesn't keep other tasks waiting and busy inputs take turns.
+    pub fn set_poll_budget(&mut self, budget: usize) {
+        assert!(budget > 0, "poll budget must be positive");
+        self.poll_budget = Some(budget);
+    }
+
+    /// Stops the agent once all its inputs have ended, even if it still has
+    /// timers running. It finishes as soon as its outputs are flushed, which
+    /// ends their streams in turn, so the end of a finite input carries on
+    /// through a pipeline of such agents.
+    pub fn end_with_inputs(&mut self) {
+        self.end_with_inputs = true;
+    }
+
+    /// Limits the agent to `limit` input and timer handler invocations per
+    /// `window` of `clock` time. Once the limit is reached, pending messages
+    /// and timers wait for the next window, which models an agent with limited
+    /// processing capacity.
+    pub fn set_handler_quota(&mut self, clock: ClockHandle, limit: u32, window: Duration) {
+        self.quota = Some(Quota {
+            clock,
+            limit,
+            window,
+            window_start: None,
+            used: 0,
+            wake_scheduled: false,
+        });
+    }
+
+    /// Handles the items of `receiver`, typically a `Receiver`, but any stream
+    /// will do: an interval, a decoded socket, several merged streams.
+    /// `on_end` is called once the stream ends.
+    pub fn new_input<T, R, I, E>(&mut self, receiver: R, on_item: I, on_end: E)
+    where
+        T: 'static,
+        R: Stream<Item = T> + 'static,
+        I: FnMut(&mut S, T) + 'static,
+        E: FnMut(&mut S) + 'static,
+    {
+        self.new_input_with_options(receiver, InputOptions::new(), on_item, on_end)
+    }
+
+    pub fn new_input_with_options<T, R, I, E>(
+        &mut self,
+        receiver: R,
+        options: InputOptions,
+        on_item: I,
+        on_end: E,
+    ) where
+        T: 'static,
+        R: Stream<Item = T> + 'static,
+        I: FnMut(&mut S, T) + 'static,
+        E: FnMut(&mut S) + 'static,
+    {
+        self.add_input(Box::pin(receiver), options, on_item, on_end)
+    }
+
+    /// Like `new_input`, but passes every item through `transform` first, e.g.
+    /// to decode it. Items it transforms hand their result to `on_item`, the
+    /// others hand the error to `on_error`, which may just drop it.
+    pub fn new_input_with<T, U, X, R, F, I, H, E>(
         &mut self,
-        receiver: Receiver<T>,
+        receiver: R,
+        transform: F,
+        mut on_item: I,
+        mut on_error: H,
+        on_end: E,
+    ) where
+        T: 'static,
+        U: 'static,
+        X: 'static,
+        R: Stream<Item = T> + 'static,
+        F: FnMut(T) -> Result<U, X> + 'static,
+        I: FnMut(&mut S, U) + 'static,
+        H: FnMut(&mut S, X) + 'static,
+        E: FnMut(&mut S) + 'static,
+    {
+        self.new_input(
+            receiver.map(transform),
+            move |s: &mut S, r| match r {
+                Ok(v) => on_item(s, v),
+                Err(e) => on_error(s, e),
+            },
+            on_end,
+        )
+    }
+
+    /// Like `new_input`, but hands `on_batch` everything queued on `receiver`
+    /// at once, up to `max` items, to spread the per-message overhead.
+    pub fn new_batch_input<T, R, I, E>(&mut self, receiver: R, max: usize, on_batch: I, on_end: E)
+    where
+        T: 'static,
+        R: Stream<Item = T> + 'static,
+        I: FnMut(&mut S, Vec<T>) + 'static,
+        E: FnMut(&mut S) + 'static,
+    {
+        assert!(max > 0, "batch size must be positive");
+        self.new_input(receiver.ready_chunks(max), on_batch, on_end)
+    }
+
+    /// Like `new_input`, for sources whose messages carry an acknowledgement
+    /// token, such as a Kafka offset or an SQS receipt handle. Each message
+    /// goes to `on_item` and its token is kept for `commit`, which is called
+    /// with the tokens collected so far as set by `options`, and when the
+    /// input ends.
+    pub fn new_acked_input<T, K, R, I, C, E>(
+        &mut self,
+        receiver: R,
+        options: CommitOptions,
+        mut on_item: I,
+        commit: C,
+        mut on_end: E,
+    ) where
+        T: 'static,
+        K: 'static,
+        R: Stream<Item = (T, K)> + 'static,
+        I: FnMut(&mut S, T) + 'static,
+        C: FnMut(&mut S, Vec<K>) + 'static,
+        E: FnMut(&mut S) + 'static,
+    {
+        let batch = options.batch;
+        // Shared by the input and the commit timer. `None` once the input has
+        // ended and its last tokens are committed.
+        let tokens = Rc::new(RefCell::new(Some(Vec::new())));
+        let commit = Rc::new(RefCell::new(commit));
+        let (t, c) = (tokens.clone(), commit.clone());
+        self.new_input(
+            receiver,
+            move |s: &mut S, (v, token)| {
+                on_item(s, v);
+                let full = {
+                    let mut tokens = t.borrow_mut();
+                    let tokens = tokens.as_mut().unwrap();
+                    tokens.push(token);
+                    tokens.len() >= batch
+                };
+                if full {
+                    let tokens = mem::take(t.borrow_mut().as_mut().unwrap());
+                    (c.borrow_mut())(s, tokens);
+                }
+            },
+            {
+                let (t, c) = (tokens.clone(), commit.clone());
+                move |s: &mut S| {
+                    let tokens = t.borrow_mut().take().unwrap_or_default();
+                    if !tokens.is_empty() {
+                        (c.borrow_mut())(s, tokens);
+                    }
+                    on_end(s);
+                }
+            },
+        );
+        self.new_timer(options.clock, options.interval, move |s: &mut S| {
+            let pending = match tokens.borrow_mut().as_mut() {
+                Some(tokens) => mem::take(tokens),
+                None => return TimerRun::Stop,
+            };
+            if !pending.is_empty() {
+                (commit.borrow_mut())(s, pending);
+            }
+            TimerRun::Continue
+        });
+    }
+
+    /// Like `new_input`, for a channel of a Tokio 1.x application.
+    pub fn new_tokio_input<
+        T: 'static,
+        I: FnMut(&mut S, T) + 'static,
+        E: FnMut(&mut S) + 'static,
+    >(
+        &mut self,
+        receiver: tokio::sync::mpsc::Receiver<T>,
+        options: InputOptions,
         on_item: I,
         on_end: E,
     ) {
-        self.inputs.push(Box::new(Input {
-            receiver: Some(receiver),
-            on_item: on_item,
-            on_end: on_end,
-            phantom_data: PhantomData,
-        }));
+        self.add_input(Box::pin(TokioReceiver(receiver)), options, on_item, on_end)
+    }
+
+    /// An input of requests to answer, each handed to `on_request` with its
+    /// `Responder`. Callers send them with `Output::ask`.
+    pub fn new_request_input<T, R, I, E>(
+        &mut self,
+        receiver: Receiver<Request<T, R>>,
+        mut on_request: I,
+        on_end: E,
+    ) where
+        T: 'static,
+        R: 'static,
+        I: FnMut(&mut S, T, Responder<R>) + 'static,
+        E: FnMut(&mut S) + 'static,
+    {
+        self.new_input(receiver, move |s: &mut S, (v, r)| on_request(s, v, r), on_end);
+    }
+
+    /// An input fed through the returned address and its clones, which can be
+    /// handed around or put into an `AgentRegistry`. It ends once they are
+    /// all dropped.
+    pub fn new_mailbox<T, I, E>(&mut self, on_item: I, on_end: E) -> Address<T>
+    where
+        T: 'static,
+        I: FnMut(&mut S, T) + 'static,
+        E: FnMut(&mut S) + 'static,
+    {
+        let (sender, receiver) = unbounded();
+        self.add_input(Box::pin(receiver), InputOptions::new(), on_item, on_end);
+        Address::new(sender)
+    }
+
+    /// An input the agent can send to itself, e.g. to defer work, through the
+    /// returned address and its clones. Messages are handed to `on_item`.
+    ///
+    /// The mailbox doesn't keep the agent running: once its other inputs have
+    /// ended and its timers have stopped, the agent finishes as soon as the
+    /// mailbox is empty, even though the state may still hold an address.
+    pub fn self_mailbox<T, I>(&mut self, on_item: I) -> Address<T>
+    where
+        T: 'static,
+        I: FnMut(&mut S, T) + 'static,
+    {
+        let (sender, receiver) = unbounded();
+        let options = InputOptions {
+            passive: true,
+            ..InputOptions::new()
+        };
+        self.add_input(Box::pin(receiver), options, on_item, |_: &mut S| ());
+        Address::new(sender)
+    }
+
+    fn add_input<T: 'static, I: FnMut(&mut S, T) + 'static, E: FnMut(&mut S) + 'static>(
+        &mut self,
+        receiver: BoxedStream<T>,
+        options: InputOptions,
+        on_item: I,
+        on_end: E,
+    ) {
+        // Kept sorted by priority, highest first.
+        let at = match self.inputs.iter().position(|i| i.priority() < options.priority) {
+            Some(at) => at,
+            None => self.inputs.len(),
+        };
+        self.inputs.insert(
+            at,
+            Box::new(Input {
+                name: options.name,
+                receiver: Some(receiver),
+                passive: options.passive,
+                priority: options.priority,
+                on_item,
+                on_end,
+                phantom_data: PhantomData,
+            }),
+        );
     }
 
     pub fn new_output<T: 'static>(&mut self, sender: Sender<T>) -> Output<T> {
-        let state = Rc::new(RefCell::new(OutputState {
-            sender: Some(sender),
-            send_in_progress: false,
-            buffer: VecDeque::new(),
+        self.new_output_with_options(sender, OutputOptions::new())
+    }
+
+    /// Lets the agent create outputs while it runs, e.g. when a new consumer
+    /// connects.
+    pub fn output_factory(&self) -> OutputFactory {
+        OutputFactory {
+            sequencer: self.sequencer.clone(),
+            added: self.added_outputs.clone(),
+        }
+    }
+
+    pub fn new_output_with_options<T: 'static>(
+        &mut self,
+        sender: Sender<T>,
+        options: OutputOptions,
+    ) -> Output<T> {
+        self.add_output(Box::pin(sender.sink_map_err(drop)), options, None, None)
+    }
+
+    /// Like `new_output_with_options`, for a channel of a Tokio 1.x
+    /// application.
+    pub fn new_tokio_output<T: Send + 'static>(
+        &mut self,
+        sender: tokio::sync::mpsc::Sender<T>,
+        options: OutputOptions,
+    ) -> Output<T> {
+        let sink = PollSender::new(sender).sink_map_err(drop);
+        self.add_output(Box::pin(sink), options, None, None)
+    }
+
+    /// Like `new_output_with_options`, for an output that writes to files. A
+    /// failed write closes the output like a disconnected receiver would.
+    pub fn new_file_output<T: 'static>(
+        &mut self,
+        file: FileOutput<T>,
+        options: OutputOptions,
+    ) -> Output<T> {
+        self.add_output(Box::pin(file.sink_map_err(drop)), options, None, None)
+    }
+
+    /// Like `new_output_with_options`, but passes every message through
+    /// `encode` on its way to the sink. The encoder may keep state between
+    /// messages, e.g. to send deltas against the previous value; the receiving
+    /// side undoes it by mapping its stream through the matching decoder.
+    pub fn new_encoded_output<T, U, F>(
+        &mut self,
+        sender: Sender<U>,
+        options: OutputOptions,
+        mut encode: F,
+    ) -> Output<T>
+    where
+        T: 'static,
+        U: 'static,
+        F: FnMut(T) -> U + 'static,
+    {
+        let sink = sender
+            .sink_map_err(drop)
+            .with(move |v| future::ready(Ok(encode(v))));
+        self.add_output(Box::pin(sink), options, None, None)
+    }
+
+    /// Like `new_output_with_options`, but hands every message the sink
+    /// rejects, e.g. because its receiver is gone, to `on_error` instead of
+    /// dropping it. The handler can log it, resend it to a fallback output or
+    /// shut the agent down; such an output never fails a strict agent.
+    pub fn new_fallible_output<T, F>(
+        &mut self,
+        sender: Sender<T>,
+        options: OutputOptions,
+        on_error: F,
+    ) -> Output<T>
+    where
+        T: 'static,
+        F: FnMut(&mut S, T) + 'static,
+    {
+        let sink = Box::pin(sender.sink_map_err(drop));
+        self.add_output(sink, options, None, Some(Box::new(on_error)))
+    }
+
+    /// Like `new_output_with_options`, but hands every message shed by the
+    /// backpressure policy in `options` to `on_overflow`, so the agent can
+    /// decide what to do with it. The policy must bound the buffer.
+    pub fn new_bounded_output<T, F>(
+        &mut self,
+        sender: Sender<T>,
+        options: OutputOptions,
+        on_overflow: F,
+    ) -> Output<T>
+    where
+        T: 'static,
+        F: FnMut(&mut S, T) + 'static,
+    {
+        assert!(
+            options.backpressure != BackpressurePolicy::Unbounded,
+            "bounded output needs a bounded backpressure policy"
+        );
+        let output = self.add_output(Box::pin(sender.sink_map_err(drop)), options, None, None);
+        output.state.borrow_mut().overflow = Some(Vec::new());
+        self.add_rejected_handler(&output.state, |s| &mut s.overflow, Box::new(on_overflow));
+        output
+    }
+
+    /// Creates an output that also sends a copy of every message to `mirror`,
+    /// e.g. a shadow instance of the downstream agent. The mirror is
+    /// best-effort: a full or closed mirror never holds back or fails the
+    /// agent, and it takes no part in output ordering or barriers.
+    pub fn new_mirrored_output<T: Clone + 'static>(
+        &mut self,
+        sender: Sender<T>,
+        mirror: Sender<T>,
+        options: OutputOptions,
+    ) -> Output<T> {
+        let mirror = Mirror {
+            state: Rc::new(RefCell::new(OutputState::new(
+                None,
+                Box::pin(mirror.sink_map_err(drop)),
+                &options,
+            ))),
+            clone: T::clone,
+        };
+        self.outputs.push(Box::new(mirror.share()));
+        self.add_output(Box::pin(sender.sink_map_err(drop)), options, Some(mirror), None)
+    }
+
+    fn add_output<T: 'static>(
+        &mut self,
+        sender: BoxedSink<T>,
+        options: OutputOptions,
+        mirror: Option<Mirror<T>>,
+        on_error: Option<Box<ErrorFn<S, T>>>,
+    ) -> Output<T> {
+        let mut state = OutputState::new(Some(&self.sequencer), sender, &options);
+        state.rejected = on_error.as_ref().map(|_| Vec::new());
+        let state = Rc::new(RefCell::new(state));
+        let name = Rc::new(options.name);
+        if let Some(on_error) = on_error {
+            self.add_rejected_handler(&state, |s| &mut s.rejected, on_error);
+        }
+        self.outputs.push(Box::new(Output {
+            name: name.clone(),
+            state: state.clone(),
+            mirror: None,
+        }));
+        Output {
+            name,
+            state,
+            mirror,
+        }
+    }
+
+    // Hands the messages an output collects in `list` to `f` on the agent's
+    // next output phase.
+    fn add_rejected_handler<T: 'static>(
+        &mut self,
+        state: &Rc<RefCell<OutputState<T>>>,
+        list: fn(&mut OutputState<T>) -> &mut Option<Vec<T>>,
+        mut f: Box<ErrorFn<S, T>>,
+    ) {
+        let state = state.clone();
+        self.on_rejected.push(Box::new(move |s: &mut S| {
+            let messages = mem::take(list(&mut state.borrow_mut()).as_mut().unwrap());
+            let any = !messages.is_empty();
+            for v in messages {
+                f(s, v);
+            }
+            any
         }));
-        self.outputs.push(Box::new(Output { state: state.clone() }));
-        Output { state: state }
+    }
+
+    /// Plugs one end of a `duplex` link into the agent: incoming messages go
+    /// to `on_item` and the returned output sends to the other end.
+    pub fn new_link<Out, In, I, E>(
+        &mut self,
+        end: LinkEnd<Out, In>,
+        on_item: I,
+        on_end: E,
+    ) -> Output<Out>
+    where
+        Out: 'static,
+        In: 'static,
+        I: FnMut(&mut S, In) + 'static,
+        E: FnMut(&mut S) + 'static,
+    {
+        self.new_input(end.receiver, on_item, on_end);
+        self.new_output(end.sender)
+    }
+
+    /// Like `new_link`, but encodes the messages sent over the link and
+    /// decodes the ones received from it, see `new_encoded_output`.
+    pub fn new_encoded_link<Out, In, WireOut, WireIn, F, G, I, E>(
+        &mut self,
+        end: LinkEnd<WireOut, WireIn>,
+        encode: F,
+        decode: G,
+        on_item: I,
+        on_end: E,
+    ) -> Output<Out>
+    where
+        Out: 'static,
+        In: 'static,
+        WireOut: 'static,
+        WireIn: 'static,
+        F: FnMut(Out) -> WireOut + 'static,
+        G: FnMut(WireIn) -> In + 'static,
+        I: FnMut(&mut S, In) + 'static,
+        E: FnMut(&mut S) + 'static,
+    {
+        self.new_input(end.receiver.map(decode), on_item, on_end);
+        self.new_encoded_output(end.sender, OutputOptions::new(), encode)
+    }
+
+    /// Creates the agent's event-time tracking, for use with
+    /// `new_timestamped_input`. `on_timer` is called with each time scheduled
+    /// through the returned `Watermarks` once the watermark reaches it.
+    pub fn new_watermarks<F: FnMut(&mut S, u64) + 'static>(&mut self, on_timer: F) -> Watermarks {
+        let watermarks = Watermarks::new();
+        self.timers.push(Box::new(WatermarkTimer::new(watermarks.clone(), Box::new(on_timer))));
+        watermarks
+    }
+
+    /// An input of items stamped with their event time, which advance
+    /// `watermarks`. `on_item` gets the time along with the item, before the
+    /// watermark moves past it.
+    pub fn new_timestamped_input<T, R, I, E>(
+        &mut self,
+        receiver: R,
+        watermarks: &Watermarks,
+        mut on_item: I,
+        mut on_end: E,
+    ) where
+        T: 'static,
+        R: Stream<Item = (u64, T)> + 'static,
+        I: FnMut(&mut S, u64, T) + 'static,
+        E: FnMut(&mut S) + 'static,
+    {
+        let input = watermarks.add_input();
+        let (w1, w2) = (watermarks.clone(), watermarks.clone());
+        self.new_input(
+            receiver,
+            move |s: &mut S, (time, v)| {
+                on_item(s, time, v);
+                w1.observe(input, time);
+            },
+            move |s: &mut S| {
+                w2.end(input);
+                on_end(s);
+            },
+        )
+    }
+
+    pub fn new_barrier(&mut self) -> Barrier {
+        Barrier { sequencer: self.sequencer.clone() }
+    }
+
+    /// Creates the agent's health handle, which starts out as
+    /// `Health::Starting`. Keep it in the state to report health from handlers;
+    /// `Agent::health` watches it from outside.
+    pub fn new_health(&mut self) -> HealthHandle {
+        let handle = HealthHandle::new();
+        self.health = Some(handle.watch());
+        handle
     }
 
     pub fn new_timer<F: FnMut(&mut S) -> TimerRun + 'static>(
@@ -223,65 +1597,501 @@ impl<S: 'static> Builder<S> {
         clock: ClockHandle,
         period: Duration,
         on_timer: F,
-    ) {
-        self.timers.push(Box::new(Timer {
-            clock: clock,
-            on_timer: on_timer,
+    ) -> TimerHandle {
+        self.new_timer_with_options(clock, period, TimerOptions::new(), on_timer)
+    }
+
+    pub fn new_timer_with_options<F: FnMut(&mut S) -> TimerRun + 'static>(
+        &mut self,
+        clock: ClockHandle,
+        period: Duration,
+        options: TimerOptions,
+        on_timer: F,
+    ) -> TimerHandle {
+        let control = Rc::new(RefCell::new(TimerControl {
             on: true,
-            period: period,
+            period,
+            dirty: false,
+        }));
+        self.timers.push(Box::new(Timer {
+            name: options.name,
+            clock,
+            on_timer,
+            control: control.clone(),
             next_activation: None,
             phantom_data: PhantomData,
         }));
+        TimerHandle { control }
+    }
+
+    /// Called once when the agent finishes, after all its inputs have ended
+    /// and its timers have stopped, or when it is shut down.
+    pub fn on_stop<F: FnOnce(&mut S) + 'static>(&mut self, on_stop: F) {
+        self.on_stop = Some(Box::new(on_stop));
     }
 
     pub fn finish(self, state: S) -> Agent<S> {
+        self.finish_with(state, false, None)
+    }
+
+    /// Like `finish`, but the agent fails with an `AgentError` instead of
+    /// silently dropping messages to a closed output or unwinding through the
+    /// executor when a handler panics.
+    pub fn finish_v2(self, state: S) -> Agent<S, AgentError> {
+        self.finish_with(state, true, None)
+    }
+
+    /// Like `finish_v2`, but with an error type of the caller's choice, so
+    /// handlers can fail the agent too. After every handler call, `failed` is
+    /// asked whether the handler has left an error in the state; if so, the
+    /// agent runs no further handlers and resolves with it.
+    pub fn finish_fallible<E, F>(self, state: S, failed: F) -> Agent<S, E>
+    where
+        E: From<AgentError>,
+        F: FnMut(&mut S) -> Option<E> + 'static,
+    {
+        self.finish_with(state, true, Some(Box::new(failed)))
+    }
+
+    fn finish_with<E>(
+        self,
+        state: S,
+        strict: bool,
+        failed: Option<Box<FailFn<S, E>>>,
+    ) -> Agent<S, E> {
         Agent {
+            name: self.name,
+            strict,
+            poll_seq: 0,
+            sequencer: self.sequencer,
+            watchdog: self.watchdog,
+            quota: self.quota,
+            health: self.health,
+            shutdown_timeout: self.shutdown_timeout,
+            fair_inputs: self.fair_inputs,
+            input_turn: 0,
+            resume_input: None,
+            poll_budget: self.poll_budget,
+            end_with_inputs: self.end_with_inputs,
+            drain_until: None,
+            deadline: None,
+            on_stop: self.on_stop,
+            on_rejected: self.on_rejected,
+            shutdown: ShutdownHandle::new(),
             inputs: self.inputs,
             outputs: self.outputs,
+            added_outputs: self.added_outputs,
             timers: self.timers,
-            state: state,
+            failed,
+            state,
         }
     }
 }
 
-pub struct Agent<S> {
-    inputs: Vec<Box<PollableInput<S>>>,
-    outputs: Vec<Box<PollableOutput>>,
-    timers: Vec<Box<PollableTimer<S>>>,
+impl<S: 'static> Default for Builder<S> {
+    fn default() -> Builder<S> {
+        Builder::new()
+    }
+}
+
+pub struct Agent<S, E = ()> {
+    name: Option<String>,
+    // Built by `finish_v2`: report failures instead of ignoring them.
+    strict: bool,
+    poll_seq: u64,
+    sequencer: Rc<RefCell<Sequencer>>,
+    watchdog: Option<Watchdog<S>>,
+    quota: Option<Quota>,
+    health: Option<HealthWatch>,
+    shutdown_timeout: Option<(ClockHandle, Duration)>,
+    fair_inputs: Option<usize>,
+    // Rotates the input polled first, with `fair_inputs`.
+    input_turn: usize,
+    // The input to poll first after the poll budget ran out.
+    resume_input: Option<usize>,
+    poll_budget: Option<usize>,
+    end_with_inputs: bool,
+    // When a shutdown stops waiting for the outputs to drain.
+    drain_until: Option<Instant>,
+    deadline: Option<Deadline>,
+    on_stop: Option<Box<StopFn<S>>>,
+    // Hand the messages rejected by outputs to their error handlers. Return
+    // whether there were any.
+    on_rejected: Vec<Box<RejectedFn<S>>>,
+    shutdown: ShutdownHandle,
+    inputs: Vec<Box<dyn PollableInput<S>>>,
+    outputs: Vec<Box<dyn PollableOutput>>,
+    // Outputs created through an `OutputFactory` since the last poll.
+    added_outputs: Rc<RefCell<Vec<Box<dyn PollableOutput>>>>,
+    timers: Vec<Box<dyn PollableTimer<S>>>,
+    failed: Option<Box<FailFn<S, E>>>,
     state: S,
 }
 
-impl<S> Future for Agent<S> {
-    type Item = ();
-    type Error = ();
+// Set by `Agent::with_deadline`. `armed` once the clock will wake the agent
+// at `when`.
+struct Deadline {
+    clock: ClockHandle,
+    when: Instant,
+    armed: bool,
+}
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let mut finished = true;
-        for o in self.outputs.iter_mut() {
-            match o.poll() {
-                OutputResult::NotReady => return Ok(Async::NotReady),
-                OutputResult::Ready => (),
-                OutputResult::Closed => (),
+// Runs a handler, catching its panic if the agent is strict.
+fn guarded<R, F: FnOnce() -> R>(strict: bool, f: F) -> Result<R, AgentError> {
+    if strict {
+        panic::catch_unwind(AssertUnwindSafe(f)).map_err(AgentError::from_panic)
+    } else {
+        Ok(f())
+    }
+}
+
+impl<S, E> Agent<S, E> {
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Watches the health reported by the agent, if it was built with
+    /// `Builder::new_health`.
+    pub fn health(&self) -> Option<HealthWatch> {
+        self.health.clone()
+    }
+
+    /// A handle to stop the agent once it runs, see `ShutdownHandle::shutdown`.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Makes the agent finish once `clock` reaches `deadline`, whether or not
+    /// its inputs have ended. It then stops its inputs and timers, runs
+    /// `on_stop` and makes a last attempt to flush its outputs. An agent built
+    /// with `finish_v2` fails with `AgentError::Timeout`.
+    pub fn with_deadline(mut self, clock: ClockHandle, deadline: Instant) -> Agent<S, E> {
+        self.deadline = Some(Deadline {
+            clock,
+            when: deadline,
+            armed: false,
+        });
+        self
+    }
+
+    /// Runs the agent on a Tokio `LocalSet`, since agents are not `Send`.
+    pub fn spawn_local(self, local: &LocalSet) -> JoinHandle<Result<(), E>>
+    where
+        S: 'static,
+        E: From<AgentError> + 'static,
+    {
+        local.spawn_local(self)
+    }
+
+    /// Builds an agent on one of `pool`'s threads and runs it there. Unlike
+    /// the agent, `build` and the returned handle are `Send`, so this works
+    /// from a multi-threaded runtime; the agent talks to other threads
+    /// through its channels.
+    pub fn spawn_pinned<F>(pool: &LocalPoolHandle, build: F) -> JoinHandle<Result<(), E>>
+    where
+        F: FnOnce() -> Agent<S, E> + Send + 'static,
+        S: 'static,
+        E: From<AgentError> + Send + 'static,
+    {
+        pool.spawn_pinned(build)
+    }
+
+    // Returns false if an output is still waiting for its sink to complete.
+    fn poll_outputs(&mut self, cx: &mut Context) -> Result<bool, AgentError> {
+        self.poll_outputs_of(cx, true)
+    }
+
+    // Polls every output, or only those not marked best-effort.
+    fn poll_outputs_of(&mut self, cx: &mut Context, best_effort: bool) -> Result<bool, AgentError> {
+        let ready = self.poll_ports(cx, best_effort)?;
+        let mut rejected = false;
+        for f in self.on_rejected.iter_mut() {
+            let state = &mut self.state;
+            rejected |= guarded(self.strict, || f(state))?;
+        }
+        // The error handlers may have sent elsewhere. Anything rejected from
+        // now on waits for the next poll.
+        if rejected {
+            self.poll_ports(cx, best_effort)
+        } else {
+            Ok(ready)
+        }
+    }
+
+    fn poll_ports(&mut self, cx: &mut Context, best_effort: bool) -> Result<bool, AgentError> {
+        self.outputs.append(&mut self.added_outputs.borrow_mut());
+        loop {
+            let delivered = self.sequencer.borrow().delivered;
+            let blocking = self.sequencer.borrow().is_blocking();
+            let mut ready = true;
+            for o in self.outputs.iter_mut() {
+                if !best_effort && o.is_best_effort() {
+                    continue;
+                }
+                match o.poll(cx) {
+                    OutputResult::NotReady => ready = false,
+                    OutputResult::Failed if self.strict => {
+                        return Err(AgentError::OutputClosed(o.name()))
+                    }
+                    OutputResult::Overflowed if self.strict => {
+                        return Err(AgentError::OutputOverflow(o.name()))
+                    }
+                    _ => (),
+                }
+            }
+
+            if !blocking || self.sequencer.borrow().delivered == delivered {
+                // Let go of outputs whose handles are gone once they are done,
+                // so outputs that come and go don't pile up.
+                self.outputs.retain(|o| !o.is_abandoned());
+                return Ok(ready);
+            }
+        }
+    }
+
+    // An agent only resolves once its outputs have handed everything to their
+    // sinks, since the buffers go away with it.
+    fn is_drained(&self) -> bool {
+        self.outputs.iter().all(|o| o.is_drained())
+    }
+
+    // Drops the inputs and timers and runs `on_stop`, if it hasn't run yet.
+    fn stop(&mut self) -> Result<(), AgentError> {
+        self.inputs.clear();
+        self.timers.clear();
+        if let Some(on_stop) = self.on_stop.take() {
+            let state = &mut self.state;
+            guarded(self.strict, || on_stop(state))?;
+        }
+        Ok(())
+    }
+
+    fn poll_deadline(&mut self, cx: &mut Context) -> Poll<Result<(), AgentError>> {
+        self.stop()?;
+        self.poll_outputs(cx)?;
+        match self.strict {
+            true => Poll::Ready(Err(AgentError::Timeout)),
+            false => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(&mut self, cx: &mut Context) -> Poll<Result<(), AgentError>> {
+        self.stop()?;
+        if self.drain_until.is_none() {
+            if let Some((ref clock, timeout)) = self.shutdown_timeout {
+                let when = clock.now() + timeout;
+                clock.add_activation(cx.waker().clone(), when);
+                self.drain_until = Some(when);
             }
         }
 
+        self.poll_outputs_of(cx, false)?;
+        if self.outputs.iter().all(|o| o.is_best_effort() || o.is_drained()) {
+            self.poll_outputs(cx)?;
+        }
+        if self.is_drained() {
+            return Poll::Ready(Ok(()));
+        }
+        match (self.drain_until, &self.shutdown_timeout) {
+            (Some(when), Some((clock, _))) if clock.now() >= when => {
+                let unflushed = self
+                    .outputs
+                    .iter()
+                    .filter(|o| o.pending() > 0)
+                    .map(|o| (o.name(), o.pending()))
+                    .collect();
+                match self.strict {
+                    true => Poll::Ready(Err(AgentError::Unflushed(unflushed))),
+                    false => Poll::Ready(Ok(())),
+                }
+            }
+            _ => Poll::Pending,
+        }
+    }
+
+    fn poll_agent(&mut self, cx: &mut Context) -> Poll<Result<(), E>>
+    where
+        E: From<AgentError>,
+    {
+        self.poll_seq += 1;
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!(
+            "agent_poll",
+            agent = port_name(&self.name),
+            seq = self.poll_seq
+        ).entered();
+
+        if let Some(ref mut d) = self.deadline {
+            if d.clock.now() >= d.when {
+                return self.poll_deadline(cx).map_err(E::from);
+            }
+            if !d.armed {
+                d.clock.add_activation(cx.waker().clone(), d.when);
+                d.armed = true;
+            }
+        }
+
+        self.shutdown.register(cx.waker());
+        if self.shutdown.is_requested() {
+            return self.poll_shutdown(cx).map_err(E::from);
+        }
+
+        let mut finished = true;
+        if !self.poll_outputs(cx)? {
+            return Poll::Pending;
+        }
+
+        let strict = self.strict;
+        let state = &mut self.state;
+        let mut throttled = false;
+        let mut inputs_open = false;
         for t in self.timers.iter_mut() {
-            match t.poll(&mut self.state) {
+            if let Some(ref mut q) = self.quota {
+                if !q.available(cx) {
+                    throttled = true;
+                    break;
+                }
+            }
+            let started = self.watchdog.as_ref().map(|_| Instant::now());
+            match guarded(strict, || t.poll(state, cx))? {
                 TimerResult::Ready => finished = false,
+                TimerResult::Handled => {
+                    finished = false;
+                    if let Some(ref mut q) = self.quota {
+                        q.used += 1;
+                    }
+                }
                 TimerResult::Closed => (),
             }
+            if let (Some(w), Some(started)) = (self.watchdog.as_mut(), started) {
+                w.check(state, started);
+            }
+            if let Some(e) = self.failed.as_mut().and_then(|f| f(state)) {
+                return Poll::Ready(Err(e));
+            }
+        }
+
+        if !throttled {
+            // Priority of the inputs that had something to handle. Lower ones
+            // wait until those are empty.
+            let mut served = None;
+            let mut handled = 0;
+            let (turn, items) = match self.fair_inputs {
+                Some(items) => (self.input_turn, items),
+                None => (0, 1),
+            };
+            // Inputs of the same priority are next to each other. With fair
+            // polling each group starts at a different input every time.
+            let mut group = 0..0;
+            let mut offset = turn;
+            let mut resume = None;
+            'inputs: for k in 0..self.inputs.len() {
+                if k == group.end {
+                    let p = self.inputs[k].priority();
+                    let len = self.inputs[k..].iter().take_while(|i| i.priority() == p).count();
+                    group = k..k + len;
+                    offset = match self.resume_input {
+                        Some(r) if group.contains(&r) => r - group.start,
+                        _ => turn,
+                    };
+                }
+                let index = group.start + (k - group.start + offset) % group.len();
+                let i = &mut self.inputs[index];
+                if served.is_some_and(|p| i.priority() < p) {
+                    break;
+                }
+                for _ in 0..items {
+                    if let Some(ref mut q) = self.quota {
+                        if !q.available(cx) {
+                            throttled = true;
+                            break 'inputs;
+                        }
+                    }
+                    let started = self.watchdog.as_ref().map(|_| Instant::now());
+                    let result = guarded(strict, || i.poll(state, cx))?;
+                    if let (Some(w), Some(started)) = (self.watchdog.as_mut(), started) {
+                        w.check(state, started);
+                    }
+                    if let Some(e) = self.failed.as_mut().and_then(|f| f(state)) {
+                        return Poll::Ready(Err(e));
+                    }
+                    match result {
+                        InputResult::Ready => {
+                            finished = false;
+                            inputs_open = true;
+                            break;
+                        }
+                        InputResult::Handled => {
+                            finished = false;
+                            inputs_open = true;
+                            served = Some(i.priority());
+                            if let Some(ref mut q) = self.quota {
+                                q.used += 1;
+                            }
+                            handled += 1;
+                            // The input has already asked to be polled again.
+                            if self.poll_budget.is_some_and(|b| handled >= b) {
+                                let next = (index - group.start + 1) % group.len();
+                                resume = Some(group.start + next);
+                                break 'inputs;
+                            }
+                        }
+                        InputResult::Closed => break,
+                    }
+                }
+            }
+            self.input_turn = self.input_turn.wrapping_add(1);
+            self.resume_input = resume;
+        }
+        if throttled {
+            finished = false;
+        } else if self.end_with_inputs && !inputs_open && !self.inputs.is_empty() {
+            self.timers.clear();
+            finished = true;
         }
 
-        for i in self.inputs.iter_mut() {
-            match i.poll(&mut self.state) {
-                InputResult::Ready => finished = false,
-                InputResult::Closed => (),
+        // Handlers may have restarted timers through their handles.
+        for t in self.timers.iter_mut() {
+            t.rearm(cx);
+        }
+
+        if finished {
+            if let Some(on_stop) = self.on_stop.take() {
+                guarded(strict, || on_stop(state))?;
+                if let Some(e) = self.failed.as_mut().and_then(|f| f(state)) {
+                    return Poll::Ready(Err(e));
+                }
             }
         }
 
-        match finished {
-            false => Ok(Async::NotReady),
-            true => Ok(Async::Ready(())),
+        // Flush whatever the handlers above have sent. Deferred outputs rely on
+        // this to reach their sinks at all.
+        self.poll_outputs(cx)?;
+
+        if finished && self.is_drained() {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+// The state is never pinned, so the agent can be moved between polls.
+impl<S, E> Unpin for Agent<S, E> {}
+
+impl<S, E: From<AgentError>> Future for Agent<S, E> {
+    type Output = Result<(), E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let result = this.poll_agent(cx);
+        if let Poll::Ready(Err(_)) = result {
+            return result;
+        }
+        let state = &mut this.state;
+        match this.failed.as_mut().and_then(|f| f(state)) {
+            Some(e) => Poll::Ready(Err(e)),
+            None => result,
         }
     }
 }
@@ -0,0 +1,114 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{Debug, Write};
+use std::hash::Hash;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use futures::stream::Stream;
+
+/// Test utility that records the messages delivered over selected links and
+/// answers ordering questions about them afterwards.
+///
+/// Wrap the receiving end of every link of interest with `record` before
+/// handing it to its agent. Clones share the same log.
+pub struct OrderingOracle<T> {
+    log: Rc<RefCell<Vec<(String, T)>>>,
+}
+
+/// A receiver whose deliveries are logged by an `OrderingOracle`.
+pub struct Recorded<R, T> {
+    receiver: R,
+    link: String,
+    log: Rc<RefCell<Vec<(String, T)>>>,
+}
+
+impl<T: Clone + Debug + PartialEq> OrderingOracle<T> {
+    pub fn new() -> OrderingOracle<T> {
+        OrderingOracle {
+            log: Rc::new(RefCell::new(Vec::new())),
+        }
+    }
+
+    /// Logs every message taken from `receiver` under the name `link`.
+    pub fn record<R: Stream<Item = T> + Unpin>(&self, link: &str, receiver: R) -> Recorded<R, T> {
+        Recorded {
+            receiver,
+            link: link.to_string(),
+            log: self.log.clone(),
+        }
+    }
+
+    /// Everything delivered so far, in order, with the link it came over.
+    pub fn deliveries(&self) -> Vec<(String, T)> {
+        self.log.borrow().clone()
+    }
+
+    /// Whether `x` was delivered, on any link, before `y` first was. False if
+    /// either never was.
+    pub fn delivered_before(&self, x: &T, y: &T) -> bool {
+        let log = self.log.borrow();
+        let first = |m: &T| log.iter().position(|(_, v)| v == m);
+        match (first(x), first(y)) {
+            (Some(i), Some(j)) => i < j,
+            _ => false,
+        }
+    }
+
+    /// Checks that, per key, messages were delivered in the order of their
+    /// sequence numbers, as told by `key`. On failure, the report names the
+    /// offending pair and lists the deliveries for that key.
+    pub fn check_in_order_by<K, F>(&self, key: F) -> Result<(), String>
+    where
+        K: Eq + Hash + Debug,
+        F: Fn(&T) -> (K, u64),
+    {
+        let log = self.log.borrow();
+        let mut last: HashMap<K, (u64, usize)> = HashMap::new();
+        for (i, (link, v)) in log.iter().enumerate() {
+            let (k, seq) = key(v);
+            if let Some(&(prev, j)) = last.get(&k) {
+                if seq < prev {
+                    let mut report = format!(
+                        "key {:?}: {:?} delivered on {} after {:?} on {}\n",
+                        k, v, link, log[j].1, log[j].0
+                    );
+                    for (link, m) in log.iter().filter(|(_, m)| key(m).0 == k) {
+                        let _ = writeln!(report, "  {}: {:?}", link, m);
+                    }
+                    return Err(report);
+                }
+            }
+            last.insert(k, (seq, i));
+        }
+        Ok(())
+    }
+}
+
+impl<T: Clone + Debug + PartialEq> Default for OrderingOracle<T> {
+    fn default() -> OrderingOracle<T> {
+        OrderingOracle::new()
+    }
+}
+
+impl<T> Clone for OrderingOracle<T> {
+    fn clone(&self) -> OrderingOracle<T> {
+        OrderingOracle {
+            log: self.log.clone(),
+        }
+    }
+}
+
+impl<R: Stream<Item = T> + Unpin, T: Clone> Stream for Recorded<R, T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        let this = &mut *self;
+        let item = Pin::new(&mut this.receiver).poll_next(cx);
+        if let Poll::Ready(Some(ref v)) = item {
+            this.log.borrow_mut().push((this.link.clone(), v.clone()));
+        }
+        item
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use futures::channel::mpsc::{Receiver, Sender};
+
+use crate::{Agent, Builder, ClockHandle, LinkEnd, Output, TimerRun};
+
+/// Options for `QuorumBroadcast::new`.
+pub struct QuorumOptions {
+    quorum: usize,
+    retry_after: Duration,
+    max_retries: u32,
+}
+
+impl QuorumOptions {
+    /// A message is delivered once `quorum` subscribers have acknowledged it.
+    pub fn new(quorum: usize) -> QuorumOptions {
+        QuorumOptions {
+            quorum,
+            retry_after: Duration::new(1, 0),
+            max_retries: 3,
+        }
+    }
+
+    /// How long to wait for acknowledgements before resending. One second by
+    /// default.
+    pub fn retry_after(mut self, retry_after: Duration) -> QuorumOptions {
+        self.retry_after = retry_after;
+        self
+    }
+
+    /// How many times a message is resent before it is given up. Three by
+    /// default.
+    pub fn max_retries(mut self, max_retries: u32) -> QuorumOptions {
+        self.max_retries = max_retries;
+        self
+    }
+}
+
+type FailureFn<T> = dyn FnMut(u64, T);
+
+struct Broadcast<T> {
+    value: T,
+    acked: Vec<bool>,
+    sent_at: Instant,
+    retries: u32,
+    reached: bool,
+}
+
+/// Fans every message out to all subscribers and reports it as delivered only
+/// once a quorum of them has acknowledged it.
+///
+/// Messages are numbered in arrival order and subscribers receive `(id,
+/// message)` on their link, answering with the id. Delivered ids are reported
+/// in order. Unacknowledged messages are resent to the subscribers that have
+/// not acknowledged them, so subscribers may see duplicates. A message that
+/// still lacks its quorum after the last retry is handed to `on_failure`.
+///
+/// Once the input has ended and every message has been delivered or given up,
+/// the retry timer stops, and the agent finishes when its subscribers hang up.
+pub struct QuorumBroadcast<T> {
+    subscribers: Vec<Output<(u64, T)>>,
+    delivered: Output<u64>,
+    clock: ClockHandle,
+    options: QuorumOptions,
+    on_failure: Box<FailureFn<T>>,
+    pending: BTreeMap<u64, Broadcast<T>>,
+    next_id: u64,
+    input_ended: bool,
+}
+
+impl<T: Clone + 'static> QuorumBroadcast<T> {
+    pub fn new<F: FnMut(u64, T) + 'static>(
+        clock: ClockHandle,
+        receiver: Receiver<T>,
+        subscribers: Vec<LinkEnd<(u64, T), u64>>,
+        delivered: Sender<u64>,
+        options: QuorumOptions,
+        on_failure: F,
+    ) -> Agent<QuorumBroadcast<T>> {
+        assert!(
+            options.quorum > 0 && options.quorum <= subscribers.len(),
+            "quorum must be between 1 and the number of subscribers"
+        );
+        let mut builder = Builder::new();
+        let delivered = builder.new_output(delivered);
+        let subscribers = subscribers
+            .into_iter()
+            .enumerate()
+            .map(|(i, end)| {
+                builder.new_link(
+                    end,
+                    move |s: &mut QuorumBroadcast<T>, id: u64| s.on_ack(i, id),
+                    |_: &mut QuorumBroadcast<T>| (),
+                )
+            })
+            .collect();
+        builder.new_input(
+            receiver,
+            |s: &mut QuorumBroadcast<T>, v: T| s.on_input(v),
+            |s: &mut QuorumBroadcast<T>| s.input_ended = true,
+        );
+        builder.new_timer(clock.clone(), options.retry_after, |s: &mut QuorumBroadcast<T>| {
+            s.on_retry()
+        });
+        builder.finish(QuorumBroadcast {
+            subscribers,
+            delivered,
+            clock,
+            options,
+            on_failure: Box::new(on_failure),
+            pending: BTreeMap::new(),
+            next_id: 0,
+            input_ended: false,
+        })
+    }
+
+    fn on_input(&mut self, value: T) {
+        let id = self.next_id;
+        self.next_id += 1;
+        for s in self.subscribers.iter_mut() {
+            s.send((id, value.clone()));
+        }
+        self.pending.insert(
+            id,
+            Broadcast {
+                value,
+                acked: vec![false; self.subscribers.len()],
+                sent_at: self.clock.now(),
+                retries: 0,
+                reached: false,
+            },
+        );
+    }
+
+    fn on_ack(&mut self, subscriber: usize, id: u64) {
+        let quorum = self.options.quorum;
+        if let Some(b) = self.pending.get_mut(&id) {
+            b.acked[subscriber] = true;
+            b.reached = b.acked.iter().filter(|&&a| a).count() >= quorum;
+        }
+        self.report_delivered();
+    }
+
+    fn report_delivered(&mut self) {
+        while let Some((&id, b)) = self.pending.iter().next() {
+            if !b.reached {
+                break;
+            }
+            self.pending.remove(&id);
+            self.delivered.send(id);
+        }
+    }
+
+    fn on_retry(&mut self) -> TimerRun {
+        let now = self.clock.now();
+        let mut failed = Vec::new();
+        for (&id, b) in self.pending.iter_mut() {
+            if b.reached || now < b.sent_at + self.options.retry_after {
+                continue;
+            }
+            if b.retries == self.options.max_retries {
+                failed.push(id);
+                continue;
+            }
+            b.retries += 1;
+            b.sent_at = now;
+            for (s, _) in self.subscribers.iter_mut().zip(&b.acked).filter(|&(_, &a)| !a) {
+                s.send((id, b.value.clone()));
+            }
+        }
+        for id in failed {
+            let b = self.pending.remove(&id).unwrap();
+            (self.on_failure)(id, b.value);
+        }
+        self.report_delivered();
+        if self.input_ended && self.pending.is_empty() {
+            TimerRun::Stop
+        } else {
+            TimerRun::Continue
+        }
+    }
+}
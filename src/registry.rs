@@ -0,0 +1,43 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::Address;
+
+/// Addresses of agents by name, so code can find an agent without having
+/// its address passed down to it.
+///
+/// Clones share the same entries. Like agents, the registry stays on one
+/// thread.
+#[derive(Clone, Default)]
+pub struct AgentRegistry {
+    entries: Rc<RefCell<HashMap<String, Box<dyn Any>>>>,
+}
+
+impl AgentRegistry {
+    pub fn new() -> AgentRegistry {
+        AgentRegistry::default()
+    }
+
+    /// Registers `address` under `name`, replacing any address registered
+    /// there before.
+    pub fn register<T: 'static>(&self, name: &str, address: Address<T>) {
+        self.entries.borrow_mut().insert(name.to_string(), Box::new(address));
+    }
+
+    /// Drops the address registered under `name`. An agent's mailbox input
+    /// only ends once every address to it, including this one, is gone.
+    pub fn unregister(&self, name: &str) {
+        self.entries.borrow_mut().remove(name);
+    }
+
+    /// The address registered under `name`, if there is one taking `T`.
+    pub fn lookup<T: 'static>(&self, name: &str) -> Option<Address<T>> {
+        self.entries
+            .borrow()
+            .get(name)
+            .and_then(|a| a.downcast_ref::<Address<T>>())
+            .cloned()
+    }
+}
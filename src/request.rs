@@ -0,0 +1,37 @@
+use futures::channel::oneshot;
+
+use crate::Output;
+
+/// A request as it travels to an agent's request input: the payload and the
+/// means to answer it.
+pub type Request<T, R> = (T, Responder<R>);
+
+/// Answers a single request, see `Builder::new_request_input`.
+///
+/// Dropping it unanswered cancels the caller's reply.
+pub struct Responder<R> {
+    sender: oneshot::Sender<R>,
+}
+
+impl<R> Responder<R> {
+    /// A responder and the reply it resolves, for callers outside an agent.
+    pub fn pair() -> (Responder<R>, oneshot::Receiver<R>) {
+        let (sender, receiver) = oneshot::channel();
+        (Responder { sender }, receiver)
+    }
+
+    /// Sends the reply. It is dropped if the caller no longer waits for it.
+    pub fn respond(self, reply: R) {
+        let _ = self.sender.send(reply);
+    }
+}
+
+impl<T, R> Output<Request<T, R>> {
+    /// Sends `request` and returns the reply to it, which fails with
+    /// `Canceled` if the request is dropped unanswered.
+    pub fn ask(&mut self, request: T) -> oneshot::Receiver<R> {
+        let (responder, reply) = Responder::pair();
+        self.send((request, responder));
+        reply
+    }
+}
@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::channel::mpsc::{Receiver, Sender};
+use futures::sink::Sink;
+use futures::stream::Stream;
+
+use crate::ClockHandle;
+
+type KeyFn<T, K> = dyn Fn(&T) -> K;
+type FactoryFn<T, K> = dyn FnMut(&K) -> Sender<T>;
+type TeardownFn<K> = dyn FnMut(&K);
+type UndeliveredFn<T, K> = dyn FnMut(&K, T);
+
+struct Session<T> {
+    sender: Sender<T>,
+    last_active: Instant,
+}
+
+/// Routes every message to a per-session child, keeping related messages on
+/// the same child.
+///
+/// The first message for a session key calls the factory, which spawns the
+/// child and returns its input. A session that gets no message for
+/// `idle_timeout` of clock time is torn down by dropping that input, which
+/// ends the child's input stream once the child has taken the messages still
+/// buffered in it; a child that wants to hand its final state back can send
+/// it from its `on_end` handler. A message whose child has gone away is handed
+/// to the `on_undelivered` hook, if any, and the session is torn down. The
+/// router finishes once its own input ends, tearing down all sessions.
+pub struct SessionRouter<K, T> {
+    receiver: Receiver<T>,
+    key: Box<KeyFn<T, K>>,
+    factory: Box<FactoryFn<T, K>>,
+    on_teardown: Option<Box<TeardownFn<K>>>,
+    on_undelivered: Option<Box<UndeliveredFn<T, K>>>,
+    clock: ClockHandle,
+    idle_timeout: Duration,
+    sessions: HashMap<K, Session<T>>,
+    // A message taken from the input whose child was not ready for it.
+    pending: Option<(K, T)>,
+    sweep_at: Option<Instant>,
+    ended: bool,
+}
+
+impl<K: Hash + Eq + Clone, T> SessionRouter<K, T> {
+    pub fn new<F, G>(
+        clock: ClockHandle,
+        idle_timeout: Duration,
+        receiver: Receiver<T>,
+        key: F,
+        factory: G,
+    ) -> SessionRouter<K, T>
+    where
+        F: Fn(&T) -> K + 'static,
+        G: FnMut(&K) -> Sender<T> + 'static,
+    {
+        SessionRouter {
+            receiver,
+            key: Box::new(key),
+            factory: Box::new(factory),
+            on_teardown: None,
+            on_undelivered: None,
+            clock,
+            idle_timeout,
+            sessions: HashMap::new(),
+            pending: None,
+            sweep_at: None,
+            ended: false,
+        }
+    }
+
+    /// Called with the key of every session that is torn down, for whatever
+    /// reason.
+    pub fn on_teardown<F: FnMut(&K) + 'static>(mut self, f: F) -> SessionRouter<K, T> {
+        self.on_teardown = Some(Box::new(f));
+        self
+    }
+
+    /// Called with the messages that could not be delivered because their
+    /// child had gone away.
+    pub fn on_undelivered<F: FnMut(&K, T) + 'static>(mut self, f: F) -> SessionRouter<K, T> {
+        self.on_undelivered = Some(Box::new(f));
+        self
+    }
+
+    /// Number of live sessions.
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    // Hands the pending message to its child. Returns false if the child is
+    // not ready for it yet.
+    fn route(&mut self, cx: &mut Context) -> bool {
+        let (key, value) = match self.pending.take() {
+            Some(p) => p,
+            None => return true,
+        };
+        let now = self.clock.now();
+        if !self.sessions.contains_key(&key) {
+            let sender = (self.factory)(&key);
+            self.sessions.insert(key.clone(), Session { sender, last_active: now });
+        }
+        let session = self.sessions.get_mut(&key).unwrap();
+        match Pin::new(&mut session.sender).poll_ready(cx) {
+            Poll::Ready(Ok(())) => {
+                session.last_active = now;
+                match session.sender.try_send(value) {
+                    Ok(()) => true,
+                    Err(e) if e.is_full() => {
+                        self.pending = Some((key, e.into_inner()));
+                        false
+                    }
+                    Err(e) => {
+                        if let Some(ref mut f) = self.on_undelivered {
+                            f(&key, e.into_inner());
+                        }
+                        self.teardown(&key);
+                        true
+                    }
+                }
+            }
+            Poll::Pending => {
+                self.pending = Some((key, value));
+                false
+            }
+            Poll::Ready(Err(_)) => {
+                if let Some(ref mut f) = self.on_undelivered {
+                    f(&key, value);
+                }
+                self.teardown(&key);
+                true
+            }
+        }
+    }
+
+    fn teardown(&mut self, key: &K) {
+        self.sessions.remove(key);
+        if let Some(ref mut f) = self.on_teardown {
+            f(key);
+        }
+    }
+
+    fn sweep(&mut self, cx: &mut Context) {
+        let now = self.clock.now();
+        let idle_timeout = self.idle_timeout;
+        let busy = self.pending.as_ref().map(|p| p.0.clone());
+        let idle: Vec<K> = self
+            .sessions
+            .iter()
+            .filter(|&(k, s)| Some(k) != busy.as_ref() && now >= s.last_active + idle_timeout)
+            .map(|(k, _)| k.clone())
+            .collect();
+        for k in idle {
+            self.teardown(&k);
+        }
+
+        let next = self.sessions.values().map(|s| s.last_active + idle_timeout).min();
+        if let Some(when) = next {
+            if self.sweep_at != Some(when) {
+                self.clock.add_activation(cx.waker().clone(), when);
+                self.sweep_at = Some(when);
+            }
+        }
+    }
+}
+
+// Keys and messages are only held, never polled in place.
+impl<K, T> Unpin for SessionRouter<K, T> {}
+
+impl<K: Hash + Eq + Clone, T> Future for SessionRouter<K, T> {
+    type Output = Result<(), ()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        while !this.ended && this.route(cx) {
+            match Pin::new(&mut this.receiver).poll_next(cx) {
+                Poll::Ready(Some(v)) => this.pending = Some(((this.key)(&v), v)),
+                Poll::Ready(None) => this.ended = true,
+                Poll::Pending => break,
+            }
+        }
+        if this.ended && this.route(cx) {
+            let keys: Vec<K> = this.sessions.keys().cloned().collect();
+            for k in keys {
+                this.teardown(&k);
+            }
+            return Poll::Ready(Ok(()));
+        }
+        this.sweep(cx);
+        Poll::Pending
+    }
+}
@@ -0,0 +1,114 @@
+use std::cell::RefCell;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+
+use futures::stream::Stream;
+
+struct ViewState<T> {
+    value: Arc<T>,
+    epoch: u64,
+    open: bool,
+    watchers: Vec<Waker>,
+}
+
+/// Publishes immutable snapshots of some data for other agents to read, e.g.
+/// a configuration or routing table.
+///
+/// Every snapshot gets the next epoch number. Dropping the publisher ends the
+/// change streams of its views.
+pub struct ViewPublisher<T> {
+    state: Rc<RefCell<ViewState<T>>>,
+}
+
+/// Reads the latest snapshot published through a `ViewPublisher`.
+///
+/// As a stream it yields the current snapshot with its epoch once and then
+/// the latest one after every change, skipping any it had no chance to see.
+/// It can be plugged into an agent with `Builder::new_input`.
+pub struct SharedView<T> {
+    state: Rc<RefCell<ViewState<T>>>,
+    seen: Option<u64>,
+}
+
+impl<T> ViewPublisher<T> {
+    /// Starts out with `initial` as epoch 0.
+    pub fn new(initial: T) -> ViewPublisher<T> {
+        ViewPublisher {
+            state: Rc::new(RefCell::new(ViewState {
+                value: Arc::new(initial),
+                epoch: 0,
+                open: true,
+                watchers: Vec::new(),
+            })),
+        }
+    }
+
+    /// Replaces the snapshot and returns its epoch.
+    pub fn publish(&mut self, value: T) -> u64 {
+        let mut s = self.state.borrow_mut();
+        s.value = Arc::new(value);
+        s.epoch += 1;
+        for w in s.watchers.drain(..) {
+            w.wake();
+        }
+        s.epoch
+    }
+
+    pub fn view(&self) -> SharedView<T> {
+        SharedView {
+            state: self.state.clone(),
+            seen: None,
+        }
+    }
+}
+
+impl<T> Drop for ViewPublisher<T> {
+    fn drop(&mut self) {
+        let mut s = self.state.borrow_mut();
+        s.open = false;
+        for w in s.watchers.drain(..) {
+            w.wake();
+        }
+    }
+}
+
+impl<T> SharedView<T> {
+    pub fn latest(&self) -> Arc<T> {
+        self.state.borrow().value.clone()
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.state.borrow().epoch
+    }
+}
+
+impl<T> Clone for SharedView<T> {
+    fn clone(&self) -> SharedView<T> {
+        SharedView {
+            state: self.state.clone(),
+            seen: None,
+        }
+    }
+}
+
+impl<T> Stream for SharedView<T> {
+    type Item = (u64, Arc<T>);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let state = self.state.clone();
+        let mut s = state.borrow_mut();
+        if self.seen == Some(s.epoch) {
+            if !s.open {
+                return Poll::Ready(None);
+            }
+            if !s.watchers.iter().any(|w| w.will_wake(cx.waker())) {
+                s.watchers.push(cx.waker().clone());
+            }
+            return Poll::Pending;
+        }
+        self.seen = Some(s.epoch);
+        Poll::Ready(Some((s.epoch, s.value.clone())))
+    }
+}
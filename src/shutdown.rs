@@ -0,0 +1,45 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::Waker;
+
+use futures::task::AtomicWaker;
+
+struct ShutdownState {
+    requested: AtomicBool,
+    waker: AtomicWaker,
+}
+
+/// Stops a running agent from outside, obtained from `Agent::shutdown_handle`.
+///
+/// Unlike the agent itself, the handle can be sent to other threads.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    state: Arc<ShutdownState>,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new() -> ShutdownHandle {
+        ShutdownHandle {
+            state: Arc::new(ShutdownState {
+                requested: AtomicBool::new(false),
+                waker: AtomicWaker::new(),
+            }),
+        }
+    }
+
+    /// Asks the agent to stop: it drops its inputs and timers, runs `on_stop`,
+    /// waits for its outputs to hand their buffered messages to their sinks and
+    /// then resolves.
+    pub fn shutdown(&self) {
+        self.state.requested.store(true, Ordering::SeqCst);
+        self.state.waker.wake();
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.state.requested.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn register(&self, waker: &Waker) {
+        self.state.waker.register(waker);
+    }
+}
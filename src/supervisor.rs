@@ -0,0 +1,149 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use crate::{Agent, AgentError, ClockHandle};
+
+type BoxedChild = Pin<Box<dyn Future<Output = Result<(), AgentError>>>>;
+type FactoryFn = dyn FnMut() -> BoxedChild;
+
+/// Which children a `Supervisor` restarts when one of them fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartStrategy {
+    /// Only the child that failed.
+    OneForOne,
+    /// All children, including those that have finished.
+    AllForOne,
+}
+
+struct Child {
+    factory: Box<FactoryFn>,
+    running: Option<BoxedChild>,
+}
+
+impl Child {
+    fn start(&mut self) {
+        self.running = Some((self.factory)());
+    }
+}
+
+/// Runs agents built by factories and restarts them when they fail.
+///
+/// Children must be built with `Builder::finish_v2`, so that a panic or a
+/// closed output ends them with an error. A child that finishes normally is
+/// not restarted. If more than `max_restarts` restarts happen within `window`
+/// of clock time, the supervisor gives up, drops its children and fails with
+/// the error that broke the limit. It finishes once all children have.
+pub struct Supervisor {
+    clock: ClockHandle,
+    strategy: RestartStrategy,
+    max_restarts: usize,
+    window: Duration,
+    restarts: VecDeque<Instant>,
+    children: Vec<Child>,
+}
+
+impl Supervisor {
+    /// Allows 3 restarts in 5 seconds, see `max_restarts`.
+    pub fn new(clock: ClockHandle, strategy: RestartStrategy) -> Supervisor {
+        Supervisor {
+            clock,
+            strategy,
+            max_restarts: 3,
+            window: Duration::new(5, 0),
+            restarts: VecDeque::new(),
+            children: Vec::new(),
+        }
+    }
+
+    pub fn max_restarts(mut self, max_restarts: usize, window: Duration) -> Supervisor {
+        self.max_restarts = max_restarts;
+        self.window = window;
+        self
+    }
+
+    /// Starts a child from `factory`, which is called again for every
+    /// restart.
+    pub fn add<S, F>(&mut self, mut factory: F)
+    where
+        S: 'static,
+        F: FnMut() -> Agent<S, AgentError> + 'static,
+    {
+        let mut child = Child {
+            factory: Box::new(move || Box::pin(factory()) as BoxedChild),
+            running: None,
+        };
+        child.start();
+        self.children.push(child);
+    }
+
+    pub fn len(&self) -> usize {
+        self.children.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.children.is_empty()
+    }
+
+    // Records a restart. Returns false if that is one too many.
+    fn allow_restart(&mut self) -> bool {
+        let now = self.clock.now();
+        while let Some(&t) = self.restarts.front() {
+            if now < t + self.window {
+                break;
+            }
+            self.restarts.pop_front();
+        }
+        self.restarts.push_back(now);
+        self.restarts.len() <= self.max_restarts
+    }
+}
+
+impl Future for Supervisor {
+    type Output = Result<(), AgentError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut failed = Vec::new();
+        for (i, c) in this.children.iter_mut().enumerate() {
+            let result = match c.running {
+                Some(ref mut f) => f.as_mut().poll(cx),
+                None => continue,
+            };
+            match result {
+                Poll::Ready(Ok(())) => c.running = None,
+                Poll::Ready(Err(e)) => {
+                    c.running = None;
+                    failed.push((i, e));
+                }
+                Poll::Pending => (),
+            }
+        }
+
+        for (i, e) in failed {
+            if !this.allow_restart() {
+                this.children.clear();
+                return Poll::Ready(Err(e));
+            }
+            // The new children have not been polled yet.
+            cx.waker().wake_by_ref();
+            match this.strategy {
+                RestartStrategy::OneForOne => this.children[i].start(),
+                RestartStrategy::AllForOne => {
+                    for c in this.children.iter_mut() {
+                        c.start();
+                    }
+                    break;
+                }
+            }
+        }
+
+        if this.children.iter().all(|c| c.running.is_none()) {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    }
+}
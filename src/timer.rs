@@ -1,23 +1,36 @@
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
-use futures::task::Task;
+use std::task::Waker;
 
-trait ClockState {
+use futures::future;
+use futures::pin_mut;
+use tokio::runtime::Handle;
+use tokio::sync::Notify;
+
+/// A time source for timers.
+///
+/// Implement it to drive timers from something other than `MockClock` or
+/// `SystemClock`, e.g. recorded timestamps, and wrap it with `ClockHandle::new`.
+pub trait Clock {
     fn now(&self) -> Instant;
-    fn add_activation(&mut self, task: Task, when: Instant);
+    /// Asks the clock to wake `waker` once `now()` reaches `when`. The timer
+    /// registers each activation once, so the clock must keep it until then.
+    fn add_activation(&mut self, waker: Waker, when: Instant);
 }
 
 pub struct ClockHandle {
-    clock: Rc<RefCell<ClockState>>,
+    clock: Rc<RefCell<dyn Clock>>,
 }
 
 #[derive(Debug)]
 struct Activation {
     when: Instant,
-    task: Task,
+    waker: Waker,
 }
 
 struct MockClockState {
@@ -29,39 +42,48 @@ pub struct MockClock {
     state: Rc<RefCell<MockClockState>>,
 }
 
-fn insert_activation(list: &mut VecDeque<Activation>, when: Instant, task: Task) {
+// Shared with the clock's task, which sleeps until the earliest activation
+// and is woken to re-arm when an earlier one is added.
+struct SystemClockShared {
+    activations: Mutex<VecDeque<Activation>>,
+    rearm: Notify,
+    closed: AtomicBool,
+}
+
+struct SystemClockState {
+    shared: Arc<SystemClockShared>,
+}
+
+/// Real-time clock that wakes timers through a tokio runtime, which must have
+/// its time driver enabled. A single task per clock sleeps until the next
+/// activation.
+pub struct SystemClock {
+    state: Rc<RefCell<SystemClockState>>,
+}
+
+fn insert_activation(list: &mut VecDeque<Activation>, when: Instant, waker: Waker) {
     let activation = Activation {
-        when: when,
-        task: task,
+        when,
+        waker,
     };
 
-    // Quick check if we need to append at the end.
-    let mut append_at_end = false;
-    match list.back() {
-        Some(a) if a.when > when => (),
-        _ => append_at_end = true,
-    }
-    if append_at_end {
-        list.push_back(activation);
-        return;
-    }
-
-    // Use binary search to find the right place.
-    let mut i0 = 0;
-    let mut i1 = list.len();
-    while i1 - i0 > 1 {
-        let i = (i0 + i1) / 2;
-        if list[i].when < when { i0 = i } else { i1 = i }
-    }
-    list.insert(i1, activation)
+    // After any activations for the same instant, so they fire in the order
+    // they were added.
+    let i = list.partition_point(|a| a.when <= when);
+    list.insert(i, activation)
 }
 
 impl ClockHandle {
+    /// The caller keeps its own reference to `clock` to move it forward.
+    pub fn new<C: Clock + 'static>(clock: Rc<RefCell<C>>) -> ClockHandle {
+        ClockHandle { clock }
+    }
+
     pub fn now(&self) -> Instant {
         self.clock.borrow().now()
     }
-    pub(crate) fn add_activation(&self, task: Task, when: Instant) {
-        self.clock.borrow_mut().add_activation(task, when)
+    pub(crate) fn add_activation(&self, waker: Waker, when: Instant) {
+        self.clock.borrow_mut().add_activation(waker, when)
     }
 }
 
@@ -71,12 +93,12 @@ impl Clone for ClockHandle {
     }
 }
 
-impl ClockState for MockClockState {
+impl Clock for MockClockState {
     fn now(&self) -> Instant {
         self.current
     }
-    fn add_activation(&mut self, task: Task, when: Instant) {
-        insert_activation(&mut self.activations, when, task)
+    fn add_activation(&mut self, waker: Waker, when: Instant) {
+        insert_activation(&mut self.activations, when, waker)
     }
 }
 
@@ -91,7 +113,7 @@ impl MockClock {
 
     pub fn advance(&mut self, duration: Duration) {
         let mut state = self.state.borrow_mut();
-        state.current = state.current + duration;
+        state.current += duration;
 
         loop {
             match state.activations.front() {
@@ -100,9 +122,86 @@ impl MockClock {
             }
 
             let activation = state.activations.pop_front().unwrap();
-            activation.task.notify()
+            activation.waker.wake()
+        }
+    }
+
+    /// The earliest pending activation, if any.
+    pub fn next_activation(&self) -> Option<Instant> {
+        self.state.borrow().activations.front().map(|a| a.when)
+    }
+
+    /// Skips idle time: advances straight to the next pending activation, but
+    /// by no more than `max_jump`. Returns how far the clock moved, or `None`
+    /// without moving it if nothing is pending.
+    pub fn advance_to_next_activation(&mut self, max_jump: Duration) -> Option<Duration> {
+        let next = self.next_activation()?;
+        let now = self.state.borrow().current;
+        let jump = if next > now { next - now } else { Duration::new(0, 0) };
+        let jump = jump.min(max_jump);
+        self.advance(jump);
+        Some(jump)
+    }
+
+    pub fn handle(&self) -> ClockHandle {
+        ClockHandle { clock: self.state.clone() }
+    }
+}
+
+impl Clock for SystemClockState {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+    fn add_activation(&mut self, waker: Waker, when: Instant) {
+        let mut list = self.shared.activations.lock().unwrap();
+        insert_activation(&mut list, when, waker);
+        if list.front().map(|a| a.when) == Some(when) {
+            self.shared.rearm.notify_one();
+        }
+    }
+}
+
+impl Drop for SystemClockState {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::SeqCst);
+        self.shared.rearm.notify_one();
+    }
+}
+
+async fn drive_system_clock(shared: Arc<SystemClockShared>) {
+    while !shared.closed.load(Ordering::SeqCst) {
+        let next = {
+            let mut list = shared.activations.lock().unwrap();
+            let now = Instant::now();
+            while list.front().is_some_and(|a| a.when <= now) {
+                list.pop_front().unwrap().waker.wake();
+            }
+            list.front().map(|a| a.when)
+        };
+        // A notification sent since the lock was released is kept for us.
+        let rearm = shared.rearm.notified();
+        match next {
+            Some(when) => {
+                let sleep = tokio::time::sleep_until(tokio::time::Instant::from_std(when));
+                pin_mut!(sleep, rearm);
+                future::select(sleep, rearm).await;
+            }
+            None => rearm.await,
         }
     }
+}
+
+impl SystemClock {
+    pub fn new(handle: &Handle) -> SystemClock {
+        let shared = Arc::new(SystemClockShared {
+            activations: Mutex::new(VecDeque::new()),
+            rearm: Notify::new(),
+            closed: AtomicBool::new(false),
+        });
+        handle.spawn(drive_system_clock(shared.clone()));
+        let state = SystemClockState { shared };
+        SystemClock { state: Rc::new(RefCell::new(state)) }
+    }
 
     pub fn handle(&self) -> ClockHandle {
         ClockHandle { clock: self.state.clone() }
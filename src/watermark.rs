@@ -0,0 +1,131 @@
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::rc::Rc;
+use std::task::Context;
+
+use crate::{PollableTimer, TimerResult};
+
+struct WatermarkState {
+    // Latest event time seen on each input, `None` before its first item.
+    inputs: Vec<Option<u64>>,
+    ended: Vec<bool>,
+    timers: BTreeSet<u64>,
+}
+
+impl WatermarkState {
+    fn current(&self) -> Option<u64> {
+        self.inputs
+            .iter()
+            .zip(&self.ended)
+            .filter(|&(_, &ended)| !ended)
+            .try_fold(u64::MAX, |min, (&seen, _)| seen.map(|t| t.min(min)))
+    }
+}
+
+/// Tracks the event time of an agent's timestamped inputs, obtained from
+/// `Builder::new_watermarks`.
+///
+/// The watermark of an input is the latest event time seen on it and the
+/// agent's watermark is the lowest of those, not counting inputs that have
+/// ended. Event-time timers fire once the agent's watermark reaches them;
+/// whatever is left fires when all inputs have ended.
+#[derive(Clone)]
+pub struct Watermarks {
+    state: Rc<RefCell<WatermarkState>>,
+}
+
+impl Watermarks {
+    pub(crate) fn new() -> Watermarks {
+        Watermarks {
+            state: Rc::new(RefCell::new(WatermarkState {
+                inputs: Vec::new(),
+                ended: Vec::new(),
+                timers: BTreeSet::new(),
+            })),
+        }
+    }
+
+    pub(crate) fn add_input(&self) -> usize {
+        let mut s = self.state.borrow_mut();
+        s.inputs.push(None);
+        s.ended.push(false);
+        s.inputs.len() - 1
+    }
+
+    pub(crate) fn observe(&self, input: usize, time: u64) {
+        let seen = &mut self.state.borrow_mut().inputs[input];
+        *seen = Some(seen.map_or(time, |t| t.max(time)));
+    }
+
+    pub(crate) fn end(&self, input: usize) {
+        self.state.borrow_mut().ended[input] = true;
+    }
+
+    /// The agent's watermark, `None` until every input has seen an item and
+    /// `u64::MAX` once they have all ended. Items stamped earlier than the
+    /// watermark are late.
+    pub fn current(&self) -> Option<u64> {
+        self.state.borrow().current()
+    }
+
+    /// Has the agent's event-time handler called with `time` once the
+    /// watermark reaches it. Scheduling the same time twice fires it once.
+    pub fn schedule(&mut self, time: u64) {
+        self.state.borrow_mut().timers.insert(time);
+    }
+}
+
+type EventTimerFn<S> = dyn FnMut(&mut S, u64);
+
+// Fires the event-time timers of `Watermarks`. Runs with the agent's other
+// timers, but is driven by the inputs: they wake the agent for every item.
+pub(crate) struct WatermarkTimer<S> {
+    watermarks: Watermarks,
+    on_timer: Box<EventTimerFn<S>>,
+}
+
+impl<S> WatermarkTimer<S> {
+    pub(crate) fn new(
+        watermarks: Watermarks,
+        on_timer: Box<EventTimerFn<S>>,
+    ) -> WatermarkTimer<S> {
+        WatermarkTimer {
+            watermarks,
+            on_timer,
+        }
+    }
+}
+
+impl<S> PollableTimer<S> for WatermarkTimer<S> {
+    fn poll(&mut self, state: &mut S, cx: &mut Context) -> TimerResult {
+        let mut fired = false;
+        loop {
+            let due = {
+                let mut s = self.watermarks.state.borrow_mut();
+                match (s.current(), s.timers.iter().next()) {
+                    (Some(w), Some(&t)) if t <= w => s.timers.take(&t),
+                    _ => None,
+                }
+            };
+            match due {
+                // The handler may schedule more through its own handle.
+                Some(t) => (self.on_timer)(state, t),
+                None => break,
+            }
+            fired = true;
+        }
+
+        let s = self.watermarks.state.borrow();
+        if fired {
+            // Report the timer as closed on the next poll if that was all.
+            cx.waker().wake_by_ref();
+            TimerResult::Handled
+        } else if s.timers.is_empty() && s.ended.iter().all(|&e| e) {
+            TimerResult::Closed
+        } else {
+            TimerResult::Ready
+        }
+    }
+
+    fn rearm(&mut self, _cx: &mut Context) {}
+}
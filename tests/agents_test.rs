@@ -1,13 +1,14 @@
-extern crate agents;
-extern crate futures;
-extern crate tokio_core;
-
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::task::Waker;
 use std::time::{Duration, Instant};
 
 use agents::*;
-use futures::{Sink, Stream};
-use futures::sync::mpsc::{channel, Receiver, Sender};
-use tokio_core::reactor::Core;
+use futures::channel::mpsc::{channel, Receiver, Sender};
+use futures::executor::LocalPool;
+use futures::task::LocalSpawnExt;
+use futures::{FutureExt, SinkExt, StreamExt};
 
 struct Passthrough {
     output: Output<i32>,
@@ -34,20 +35,39 @@ impl Passthrough {
 
 #[test]
 fn passthrough() {
-    let (tx1, rx1) = channel(1);
+    let (mut tx1, rx1) = channel(1);
     let (tx2, rx2) = channel(1);
     let c = Passthrough::new(rx1, tx2);
 
-    let mut core = Core::new().unwrap();
+    let mut pool = LocalPool::new();
 
-    core.handle().spawn(c);
+    pool.spawner().spawn_local(c.map(drop)).unwrap();
 
-    core.run(tx1.send(42)).unwrap();
+    pool.run_until(tx1.send(42)).unwrap();
 
-    let out = core.run(rx2.take(1).collect()).unwrap();
+    let out = pool.run_until(rx2.take(1).collect::<Vec<_>>());
     assert_eq!(out, vec![42])
 }
 
+#[test]
+fn takes_every_queued_message() {
+    let (mut tx1, rx1) = channel(4);
+    let (tx2, mut rx2) = channel(4);
+    for i in 1..=3 {
+        tx1.try_send(i).unwrap();
+    }
+    let c = Passthrough::new(rx1, tx2);
+
+    let mut pool = LocalPool::new();
+
+    // The receiver wakes the agent only once, for the first message.
+    pool.spawner().spawn_local(c.map(drop)).unwrap();
+    pool.run_until_stalled();
+    for i in 1..=3 {
+        assert_eq!(rx2.try_recv().ok(), Some(i));
+    }
+}
+
 struct Periodic {
     output: Output<i32>,
     count: i32,
@@ -68,7 +88,7 @@ impl Periodic {
 
     fn on_timer(&mut self) -> TimerRun {
         self.output.send(self.count);
-        self.count = self.count + 1;
+        self.count += 1;
         TimerRun::Continue
     }
 }
@@ -79,16 +99,2675 @@ fn periodic() {
     let (tx, mut rx) = channel(1);
     let c = Periodic::new(clock.handle(), tx);
 
-    let mut core = Core::new().unwrap();
+    let mut pool = LocalPool::new();
 
-    core.handle().spawn(c);
-    core.turn(None); // Poll component once to let it schedule the timer.
+    pool.spawner().spawn_local(c.map(drop)).unwrap();
+    pool.run_until_stalled(); // Poll component once to let it schedule the timer.
 
     for i in 0..10 {
         clock.advance(Duration::new(1, 0));
-        core.turn(None);
-        let (v, new_rx) = core.run(rx.into_future()).unwrap();
+        pool.run_until_stalled();
+        let v = pool.run_until(rx.next());
+        assert_eq!(i, v.unwrap());
+    }
+}
+
+struct Triple {
+    output: Output<i32>,
+}
+
+impl Triple {
+    fn new(receiver: Receiver<i32>, sender: Sender<i32>) -> Agent<Triple> {
+        let mut builder = Builder::new();
+        let out = builder.new_output_with_options(sender, OutputOptions::new().deferred());
+        builder.new_input(
+            receiver,
+            |s: &mut Triple, v: i32| s.on_input(v),
+            |_: &mut Triple| (),
+        );
+        builder.finish(Triple { output: out })
+    }
+
+    fn on_input(&mut self, val: i32) {
+        for i in 0..3 {
+            self.output.send(val * 10 + i);
+        }
+    }
+}
+
+#[test]
+fn deferred_output_preserves_order() {
+    let (mut tx1, rx1) = channel(1);
+    let (tx2, rx2) = channel(1);
+    let c = Triple::new(rx1, tx2);
+
+    let mut pool = LocalPool::new();
+
+    pool.spawner().spawn_local(c.map(drop)).unwrap();
+
+    pool.run_until(tx1.send(1)).unwrap();
+    pool.run_until(tx1.send(2)).unwrap();
+
+    let out = pool.run_until(rx2.take(6).collect::<Vec<_>>());
+    assert_eq!(out, vec![10, 11, 12, 20, 21, 22])
+}
+
+struct Splitter {
+    first: Output<i32>,
+    second: Output<i32>,
+}
+
+impl Splitter {
+    fn new(receiver: Receiver<i32>, sender: Sender<i32>) -> Agent<Splitter> {
+        let mut builder = Builder::new();
+        builder.set_ordered_outputs(true);
+        let first = builder.new_output::<i32>(sender.clone());
+        let second = builder.new_output::<i32>(sender);
+        builder.new_input(
+            receiver,
+            |s: &mut Splitter, v: i32| s.on_input(v),
+            |_: &mut Splitter| (),
+        );
+        builder.finish(Splitter { first, second })
+    }
+
+    fn on_input(&mut self, val: i32) {
+        self.first.send(val);
+        self.first.send(val + 1);
+        self.second.send(val + 2);
+    }
+}
+
+#[test]
+fn ordered_outputs() {
+    let (mut tx1, rx1) = channel(1);
+    // Each sender gets a single slot, so the second message on `first` has to
+    // wait for the consumer while `second` could otherwise overtake it.
+    let (tx2, rx2) = channel(0);
+    let c = Splitter::new(rx1, tx2);
+
+    let mut pool = LocalPool::new();
+
+    pool.spawner().spawn_local(c.map(drop)).unwrap();
+
+    pool.run_until(tx1.send(1)).unwrap();
+
+    let out = pool.run_until(rx2.take(3).collect::<Vec<_>>());
+    assert_eq!(out, vec![1, 2, 3])
+}
+
+struct Committer {
+    data: Output<i32>,
+    commits: Output<i32>,
+    barrier: Barrier,
+}
+
+impl Committer {
+    fn new(receiver: Receiver<i32>, sender: Sender<i32>) -> Agent<Committer> {
+        let mut builder = Builder::new();
+        let data = builder.new_output::<i32>(sender.clone());
+        let commits = builder.new_output::<i32>(sender);
+        let barrier = builder.new_barrier();
+        builder.new_input(
+            receiver,
+            |s: &mut Committer, v: i32| s.on_input(v),
+            |_: &mut Committer| (),
+        );
+        builder.finish(Committer {
+            data,
+            commits,
+            barrier,
+        })
+    }
+
+    fn on_input(&mut self, val: i32) {
+        self.data.send(val);
+        self.data.send(val + 1);
+        self.barrier.insert();
+        self.commits.send(-val);
+    }
+}
+
+#[test]
+fn barrier_across_outputs() {
+    let (mut tx1, rx1) = channel(1);
+    let (tx2, rx2) = channel(0);
+    let c = Committer::new(rx1, tx2);
+
+    let mut pool = LocalPool::new();
+
+    pool.spawner().spawn_local(c.map(drop)).unwrap();
+
+    pool.run_until(tx1.send(1)).unwrap();
+
+    let out = pool.run_until(rx2.take(3).collect::<Vec<_>>());
+    assert_eq!(out, vec![1, 2, -1])
+}
+
+struct Sleeper {
+    slow: Output<i32>,
+}
+
+impl Sleeper {
+    fn new(receiver: Receiver<u64>, sender: Sender<i32>) -> Agent<Sleeper> {
+        let mut builder = Builder::new();
+        let slow = builder.new_output::<i32>(sender);
+        builder.set_handler_timeout(Duration::from_millis(5), |s: &mut Sleeper, _| {
+            s.slow.send(1)
+        });
+        builder.new_input(
+            receiver,
+            |_: &mut Sleeper, ms: u64| std::thread::sleep(Duration::from_millis(ms)),
+            |_: &mut Sleeper| (),
+        );
+        builder.finish(Sleeper { slow })
+    }
+}
+
+#[test]
+fn handler_timeout() {
+    let (mut tx1, rx1) = channel(1);
+    let (tx2, rx2) = channel(1);
+    let c = Sleeper::new(rx1, tx2);
+
+    let mut pool = LocalPool::new();
+
+    pool.spawner().spawn_local(c.map(drop)).unwrap();
+
+    pool.run_until(tx1.send(50)).unwrap();
+
+    let out = pool.run_until(rx2.take(1).collect::<Vec<_>>());
+    assert_eq!(out, vec![1])
+}
+
+#[test]
+fn agent_set_with_budget() {
+    let mut set = AgentSet::with_budget(1);
+    let mut inputs = Vec::new();
+    let mut outputs = Vec::new();
+    for _ in 0..3 {
+        let (tx1, rx1) = channel(1);
+        let (tx2, rx2) = channel(1);
+        set.add(Passthrough::new(rx1, tx2));
+        inputs.push(tx1);
+        outputs.push(rx2);
+    }
+    assert_eq!(set.len(), 3);
+
+    let mut pool = LocalPool::new();
+
+    pool.spawner().spawn_local(set.map(drop)).unwrap();
+
+    for (i, (mut tx, rx)) in inputs.into_iter().zip(outputs).enumerate() {
+        pool.run_until(tx.send(i as i32)).unwrap();
+        let out = pool.run_until(rx.take(1).collect::<Vec<_>>());
+        assert_eq!(out, vec![i as i32]);
+    }
+}
+
+#[test]
+fn agent_set_staggered_start() {
+    let mut clock = MockClock::new(Instant::now());
+    let mut set = AgentSet::new();
+    set.stagger(clock.handle(), Duration::new(1, 0));
+    let mut outputs = Vec::new();
+    for i in 0..3 {
+        let (mut tx1, rx1) = channel(1);
+        let (tx2, rx2) = channel(1);
+        set.add(Passthrough::new(rx1, tx2));
+        tx1.try_send(i).unwrap();
+        outputs.push(rx2);
+    }
+
+    let mut pool = LocalPool::new();
+    pool.spawner().spawn_local(set.map(drop)).unwrap();
+
+    assert_eq!(try_next(&mut pool, &mut outputs[0]), Some(0));
+    assert_eq!(try_next(&mut pool, &mut outputs[1]), None);
+    clock.advance(Duration::new(1, 0));
+    assert_eq!(try_next(&mut pool, &mut outputs[1]), Some(1));
+    assert_eq!(try_next(&mut pool, &mut outputs[2]), None);
+    clock.advance(Duration::new(1, 0));
+    assert_eq!(try_next(&mut pool, &mut outputs[2]), Some(2));
+}
+
+struct Monitored {
+    health: HealthHandle,
+}
+
+impl Monitored {
+    fn new(receiver: Receiver<i32>) -> Agent<Monitored> {
+        let mut builder = Builder::new();
+        let health = builder.new_health();
+        builder.new_input(
+            receiver,
+            |s: &mut Monitored, v: i32| s.on_input(v),
+            |_: &mut Monitored| (),
+        );
+        builder.finish(Monitored { health })
+    }
+
+    fn on_input(&mut self, errors: i32) {
+        self.health.set(match errors {
+            0 => Health::Healthy,
+            n => Health::Degraded(format!("{} errors", n)),
+        });
+    }
+}
+
+#[test]
+fn health_watch() {
+    let (mut tx, rx) = channel(1);
+    let c = Monitored::new(rx);
+    let mut watch = c.health().unwrap();
+    assert_eq!(watch.get(), Health::Starting);
+
+    let mut pool = LocalPool::new();
+
+    pool.spawner().spawn_local(c.map(drop)).unwrap();
+
+    let h = pool.run_until(watch.next());
+    assert_eq!(h, Some(Health::Starting));
+
+    pool.run_until(tx.send(0)).unwrap();
+    let h = pool.run_until(watch.next());
+    assert_eq!(h, Some(Health::Healthy));
+
+    pool.run_until(tx.send(3)).unwrap();
+    let h = pool.run_until(watch.next());
+    assert_eq!(h, Some(Health::Degraded("3 errors".to_string())));
+}
+
+#[test]
+fn health_watch_ends_with_agent() {
+    let (mut tx, rx) = channel(1);
+    let c = Monitored::new(rx);
+    let watch = c.health().unwrap();
+
+    let mut pool = LocalPool::new();
+
+    pool.spawner().spawn_local(c.map(drop)).unwrap();
+
+    pool.run_until(tx.send(0)).unwrap();
+    drop(tx);
+    pool.run_until_stalled();
+
+    // The agent is done and gone, so the stream ends after the last health.
+    let h = pool.run_until(watch.collect::<Vec<_>>());
+    assert_eq!(h, vec![Health::Healthy]);
+}
+
+#[test]
+fn skip_idle_time() {
+    let mut clock = MockClock::new(Instant::now());
+    let (tx, mut rx) = channel(1);
+    let c = Periodic::new(clock.handle(), tx);
+
+    let mut pool = LocalPool::new();
+
+    pool.spawner().spawn_local(c.map(drop)).unwrap();
+    pool.run_until_stalled();
+
+    // The jump is capped, so the first activation is not reached yet.
+    let jump = clock.advance_to_next_activation(Duration::from_millis(300));
+    assert_eq!(jump, Some(Duration::from_millis(300)));
+
+    for i in 0..3 {
+        let jump = clock.advance_to_next_activation(Duration::new(10, 0));
+        let expected = if i == 0 { Duration::from_millis(700) } else { Duration::new(1, 0) };
+        assert_eq!(jump, Some(expected));
+        pool.run_until_stalled();
+        let v = pool.run_until(rx.next());
         assert_eq!(i, v.unwrap());
-        rx = new_rx;
     }
 }
+
+#[test]
+fn skip_idle_time_to_earliest_activation() {
+    let mut clock = MockClock::new(Instant::now());
+    let (tx, mut rx) = channel(2);
+    let mut builder = Builder::new();
+    let output = builder.new_output::<i32>(tx);
+    builder.new_timer(clock.handle(), Duration::new(10, 0), |s: &mut Passthrough| {
+        s.output.send(10);
+        TimerRun::Stop
+    });
+    builder.new_timer(clock.handle(), Duration::new(5, 0), |s: &mut Passthrough| {
+        s.output.send(5);
+        TimerRun::Stop
+    });
+    let c = builder.finish(Passthrough { output });
+
+    let mut pool = LocalPool::new();
+
+    pool.spawner().spawn_local(c.map(drop)).unwrap();
+    pool.run_until_stalled();
+
+    let start = clock.handle().now();
+    assert_eq!(clock.next_activation(), Some(start + Duration::new(5, 0)));
+    let jump = clock.advance_to_next_activation(Duration::new(60, 0));
+    assert_eq!(jump, Some(Duration::new(5, 0)));
+    assert_eq!(try_next(&mut pool, &mut rx), Some(5));
+    assert_eq!(try_next(&mut pool, &mut rx), None);
+
+    let jump = clock.advance_to_next_activation(Duration::new(60, 0));
+    assert_eq!(jump, Some(Duration::new(5, 0)));
+    assert_eq!(try_next(&mut pool, &mut rx), Some(10));
+}
+
+#[test]
+fn assert_agent_reports_violations() {
+    let (mut tx1, rx1) = channel(4);
+    let (tx2, rx2) = channel(4);
+    let violations = Rc::new(RefCell::new(Vec::new()));
+    let mut last = None;
+    let recorded = violations.clone();
+    let c = AssertAgent::new(
+        rx1,
+        tx2,
+        move |v: &i32| {
+            let ok = last.is_none_or(|l| *v > l);
+            last = Some(*v);
+            ok
+        },
+        move |n, v: &i32| recorded.borrow_mut().push((n, *v)),
+    );
+
+    let mut pool = LocalPool::new();
+
+    pool.spawner().spawn_local(c.map(drop)).unwrap();
+
+    let mut items = futures::stream::iter(vec![1, 2, 2, 5, 3]).map(Ok);
+    pool.run_until(tx1.send_all(&mut items)).unwrap();
+
+    let out = pool.run_until(rx2.take(5).collect::<Vec<_>>());
+    assert_eq!(out, vec![1, 2, 2, 5, 3]);
+    assert_eq!(*violations.borrow(), vec![(2, 2), (4, 3)]);
+}
+
+fn try_next<T>(pool: &mut LocalPool, rx: &mut Receiver<T>) -> Option<T> {
+    pool.run_until_stalled();
+    rx.try_recv().ok()
+}
+
+#[test]
+fn handler_quota() {
+    let mut clock = MockClock::new(Instant::now());
+    let (mut tx1, rx1) = channel(8);
+    let (tx2, mut rx2) = channel(8);
+    let mut builder = Builder::new();
+    let out = builder.new_output::<i32>(tx2);
+    builder.set_handler_quota(clock.handle(), 2, Duration::new(1, 0));
+    builder.new_input(
+        rx1,
+        |s: &mut Passthrough, v: i32| s.on_input(v),
+        |s: &mut Passthrough| s.on_input_end(),
+    );
+    let c = builder.finish(Passthrough { output: out });
+
+    let mut pool = LocalPool::new();
+
+    pool.spawner().spawn_local(c.map(drop)).unwrap();
+
+    let mut items = futures::stream::iter(0..5).map(Ok);
+    pool.run_until(tx1.send_all(&mut items)).unwrap();
+    pool.run_until_stalled();
+
+    let mut windows = Vec::new();
+    for _ in 0..3 {
+        let mut received = Vec::new();
+        while let Some(v) = try_next(&mut pool, &mut rx2) {
+            received.push(v);
+        }
+        windows.push(received);
+        clock.advance(Duration::new(1, 0));
+        pool.run_until_stalled();
+    }
+    assert_eq!(windows, vec![vec![0, 1], vec![2, 3], vec![4]]);
+}
+
+#[test]
+fn strict_agent_reports_closed_output() {
+    let (mut tx1, rx1) = channel(1);
+    let (tx2, rx2) = channel(1);
+    let mut builder = Builder::new();
+    let out = builder.new_output_with_options(tx2, OutputOptions::new().name("out"));
+    builder.new_input(
+        rx1,
+        |s: &mut Passthrough, v: i32| s.on_input(v),
+        |s: &mut Passthrough| s.on_input_end(),
+    );
+    let c = builder.finish_v2(Passthrough { output: out });
+    drop(rx2);
+
+    tx1.try_send(42).unwrap();
+
+    let result = LocalPool::new().run_until(c);
+    assert_eq!(result, Err(AgentError::OutputClosed(Some("out".to_string()))));
+}
+
+#[test]
+fn strict_agent_reports_panic() {
+    let (mut tx1, rx1) = channel(1);
+    let (tx2, _rx2) = channel(1);
+    let mut builder = Builder::new();
+    let out = builder.new_output::<i32>(tx2);
+    builder.new_input(
+        rx1,
+        |_: &mut Passthrough, _: i32| panic!("boom"),
+        |_: &mut Passthrough| (),
+    );
+    let c = builder.finish_v2(Passthrough { output: out });
+
+    tx1.try_send(42).unwrap();
+
+    let result = LocalPool::new().run_until(c);
+    assert_eq!(result, Err(AgentError::Panic("boom".to_string())));
+}
+
+struct Inactivity {
+    output: Output<i32>,
+    timeout: TimerHandle,
+}
+
+impl Inactivity {
+    fn new(clock: ClockHandle, receiver: Receiver<i32>, sender: Sender<i32>) -> Agent<Inactivity> {
+        let mut builder = Builder::new();
+        let out = builder.new_output::<i32>(sender);
+        let timeout = builder.new_timer(clock, Duration::new(5, 0), |s: &mut Inactivity| {
+            s.on_timeout()
+        });
+        builder.new_input(
+            receiver,
+            |s: &mut Inactivity, v: i32| s.on_input(v),
+            |_: &mut Inactivity| (),
+        );
+        builder.finish(Inactivity {
+            output: out,
+            timeout,
+        })
+    }
+
+    fn on_input(&mut self, period: i32) {
+        match period {
+            0 => self.timeout.start(),
+            p => self.timeout.reschedule(Duration::new(p as u64, 0)),
+        }
+    }
+
+    fn on_timeout(&mut self) -> TimerRun {
+        self.output.send(-1);
+        TimerRun::Stop
+    }
+}
+
+#[test]
+fn timer_handle_restarts_timeout() {
+    let mut clock = MockClock::new(Instant::now());
+    let (mut tx1, rx1) = channel(1);
+    let (tx2, mut rx2) = channel(1);
+    let c = Inactivity::new(clock.handle(), rx1, tx2);
+
+    let mut pool = LocalPool::new();
+
+    pool.spawner().spawn_local(c.map(drop)).unwrap();
+    pool.run_until_stalled();
+
+    // Activity every 3s keeps the 5s timeout from firing.
+    for _ in 0..3 {
+        clock.advance(Duration::new(3, 0));
+        pool.run_until(tx1.send(0)).unwrap();
+        pool.run_until_stalled();
+        assert_eq!(try_next(&mut pool, &mut rx2), None);
+    }
+
+    clock.advance(Duration::new(5, 0));
+    pool.run_until_stalled();
+    assert_eq!(try_next(&mut pool, &mut rx2), Some(-1));
+
+    // The handler stopped the timer.
+    clock.advance(Duration::new(5, 0));
+    pool.run_until_stalled();
+    assert_eq!(try_next(&mut pool, &mut rx2), None);
+
+    // Rescheduling restarts it with the new period.
+    pool.run_until(tx1.send(2)).unwrap();
+    pool.run_until_stalled();
+    clock.advance(Duration::new(2, 0));
+    pool.run_until_stalled();
+    assert_eq!(try_next(&mut pool, &mut rx2), Some(-1));
+}
+
+#[test]
+fn stopped_timer_ends_agent() {
+    let mut clock = MockClock::new(Instant::now());
+    let mut builder = Builder::new();
+    builder.new_timer(clock.handle(), Duration::new(1, 0), |_: &mut ()| TimerRun::Stop);
+    let c = builder.finish(());
+
+    let done = Rc::new(RefCell::new(false));
+    let d = done.clone();
+    let mut pool = LocalPool::new();
+
+    pool.spawner().spawn_local(c.map(move |_| *d.borrow_mut() = true)).unwrap();
+    pool.run_until_stalled();
+    assert!(!*done.borrow());
+
+    // No further activation is needed to see that the timer has stopped.
+    clock.advance(Duration::new(1, 0));
+    pool.run_until_stalled();
+    assert!(*done.borrow());
+}
+
+#[test]
+fn mirrored_output() {
+    let (mut tx1, rx1) = channel(1);
+    let (tx2, rx2) = channel(1);
+    let (tx3, mut rx3) = channel(1);
+    let mut builder = Builder::new();
+    let out = builder.new_mirrored_output(tx2, tx3, OutputOptions::new());
+    builder.new_input(
+        rx1,
+        |s: &mut Passthrough, v: i32| s.on_input(v),
+        |s: &mut Passthrough| s.on_input_end(),
+    );
+    let c = builder.finish_v2(Passthrough { output: out });
+
+    let mut pool = LocalPool::new();
+
+    pool.spawner()
+        .spawn_local(c.map(|r| r.unwrap_or_else(|e| panic!("agent failed: {}", e))))
+        .unwrap();
+
+    pool.run_until(tx1.send(1)).unwrap();
+    let v = pool.run_until(rx3.next());
+    assert_eq!(v, Some(1));
+
+    // A dropped mirror does not affect the primary output.
+    drop(rx3);
+    pool.run_until(tx1.send(2)).unwrap();
+    let out = pool.run_until(rx2.take(2).collect::<Vec<_>>());
+    assert_eq!(out, vec![1, 2]);
+}
+
+#[test]
+fn system_clock_drives_timers() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+    let clock = SystemClock::new(runtime.handle());
+    let (tx, rx) = channel(1);
+    let mut builder = Builder::new();
+    let out = builder.new_output::<i32>(tx);
+    builder.new_timer(clock.handle(), Duration::from_millis(10), |s: &mut Periodic| {
+        s.on_timer()
+    });
+    let c = builder.finish(Periodic {
+        output: out,
+        count: 0,
+    });
+
+    let started = Instant::now();
+    let local = tokio::task::LocalSet::new();
+    local.spawn_local(c);
+
+    let out = local.block_on(&runtime, rx.take(3).collect::<Vec<_>>());
+    assert_eq!(out, vec![0, 1, 2]);
+    assert!(started.elapsed() >= Duration::from_millis(30));
+}
+
+#[test]
+fn system_clock_sleeps_once_for_all_timers() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+    let clock = SystemClock::new(runtime.handle());
+    let mut builder = Builder::new();
+    for _ in 0..50 {
+        builder.new_timer(clock.handle(), Duration::new(3600, 0), |_: &mut ()| {
+            TimerRun::Continue
+        });
+    }
+    let c = builder.finish(());
+
+    let local = tokio::task::LocalSet::new();
+    local.spawn_local(c);
+    local.block_on(&runtime, async { tokio::time::sleep(Duration::from_millis(10)).await });
+    // Only the clock's own task is waiting.
+    assert_eq!(runtime.metrics().num_alive_tasks(), 1);
+}
+
+#[test]
+fn system_clock_fires_timers_added_out_of_order() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+    let clock = SystemClock::new(runtime.handle());
+    let (tx, rx) = channel(2);
+    let mut builder = Builder::new();
+    let output = builder.new_output::<i32>(tx);
+    builder.new_timer(clock.handle(), Duration::from_millis(500), |s: &mut Passthrough| {
+        s.output.send(500);
+        TimerRun::Stop
+    });
+    builder.new_timer(clock.handle(), Duration::from_millis(20), |s: &mut Passthrough| {
+        s.output.send(20);
+        TimerRun::Stop
+    });
+    let c = builder.finish(Passthrough { output });
+
+    let started = Instant::now();
+    let local = tokio::task::LocalSet::new();
+    local.spawn_local(c);
+
+    let out = local.block_on(&runtime, rx.take(1).collect::<Vec<_>>());
+    assert_eq!(out, vec![20]);
+    assert!(started.elapsed() < Duration::from_millis(400));
+}
+
+#[test]
+fn compare_agent_reports_differences() {
+    let mut clock = MockClock::new(Instant::now());
+    let (mut tx1, rx1) = channel(8);
+    let (mut tx2, rx2) = channel(8);
+    let (tx3, mut rx3) = channel(8);
+    let c = CompareAgent::by_sequence(clock.handle(), Duration::new(1, 0), rx1, rx2, tx3);
+
+    let mut pool = LocalPool::new();
+
+    pool.spawner().spawn_local(c.map(drop)).unwrap();
+
+    let mut items = futures::stream::iter(vec![1, 2, 3, 4]).map(Ok);
+    pool.run_until(tx1.send_all(&mut items)).unwrap();
+    let mut items = futures::stream::iter(vec![1, 5, 3]).map(Ok);
+    pool.run_until(tx2.send_all(&mut items)).unwrap();
+    let v = pool.run_until(rx3.next());
+    assert_eq!(v, Some(Difference::Mismatch { primary: 2, shadow: 5 }));
+    // The agent takes the remaining messages before the clock moves.
+    assert_eq!(try_next(&mut pool, &mut rx3), None);
+
+    // The unmatched primary message expires once the window has passed.
+    clock.advance(Duration::new(2, 0));
+    let v = pool.run_until(rx3.next());
+    assert_eq!(v, Some(Difference::MissingShadow(4)));
+}
+
+#[test]
+fn compare_agent_finishes_with_its_inputs() {
+    let mut clock = MockClock::new(Instant::now());
+    let (mut tx1, rx1) = channel(8);
+    let (tx2, rx2) = channel::<i32>(8);
+    let (tx3, rx3) = channel(8);
+    let c = CompareAgent::by_sequence(clock.handle(), Duration::new(1, 0), rx1, rx2, tx3);
+
+    let mut pool = LocalPool::new();
+
+    pool.spawner().spawn_local(c.map(drop)).unwrap();
+
+    pool.run_until(tx1.send(1)).unwrap();
+    drop(tx1);
+    drop(tx2);
+    pool.run_until_stalled();
+
+    // The unmatched message is still reported before the agent finishes.
+    clock.advance(Duration::new(2, 0));
+    let out = pool.run_until(rx3.collect::<Vec<_>>());
+    assert_eq!(out, vec![Difference::MissingShadow(1)]);
+}
+
+#[test]
+fn compare_agent_pairs_by_key() {
+    let clock = MockClock::new(Instant::now());
+    let (mut tx1, rx1) = channel(8);
+    let (mut tx2, rx2) = channel(8);
+    let (tx3, rx3) = channel(8);
+    let c = CompareAgent::new(
+        clock.handle(),
+        Duration::new(1, 0),
+        rx1,
+        rx2,
+        tx3,
+        |v: &(u32, &'static str)| v.0,
+    );
+
+    let mut pool = LocalPool::new();
+
+    pool.spawner().spawn_local(c.map(drop)).unwrap();
+
+    let mut items = futures::stream::iter(vec![(1, "a"), (2, "b")]).map(Ok);
+    pool.run_until(tx1.send_all(&mut items)).unwrap();
+    let mut items = futures::stream::iter(vec![(2, "x"), (1, "a")]).map(Ok);
+    pool.run_until(tx2.send_all(&mut items)).unwrap();
+
+    let out = pool.run_until(rx3.take(1).collect::<Vec<_>>());
+    assert_eq!(out, vec![Difference::Mismatch { primary: (2, "b"), shadow: (2, "x") }]);
+}
+
+// Replays recorded timestamps, one per `step`.
+struct ReplayClock {
+    now: Instant,
+    recorded: Vec<Instant>,
+    waiting: Vec<(Instant, Waker)>,
+}
+
+impl ReplayClock {
+    fn step(&mut self) {
+        self.now = self.recorded.remove(0);
+        let now = self.now;
+        let (due, waiting) = self.waiting.drain(..).partition(|w| w.0 <= now);
+        self.waiting = waiting;
+        for (_, w) in due {
+            w.wake();
+        }
+    }
+}
+
+impl Clock for ReplayClock {
+    fn now(&self) -> Instant {
+        self.now
+    }
+    fn add_activation(&mut self, waker: Waker, when: Instant) {
+        self.waiting.push((when, waker));
+    }
+}
+
+#[test]
+fn custom_clock_drives_timers() {
+    let start = Instant::now();
+    let clock = Rc::new(RefCell::new(ReplayClock {
+        now: start,
+        recorded: vec![start + Duration::from_millis(500), start + Duration::new(3, 0)],
+        waiting: Vec::new(),
+    }));
+    let (tx, mut rx) = channel(1);
+    let c = Periodic::new(ClockHandle::new(clock.clone()), tx);
+
+    let mut pool = LocalPool::new();
+
+    pool.spawner().spawn_local(c.map(drop)).unwrap();
+    pool.run_until_stalled();
+
+    // The first recorded timestamp is within the first period.
+    clock.borrow_mut().step();
+    pool.run_until_stalled();
+    assert_eq!(try_next(&mut pool, &mut rx), None);
+
+    clock.borrow_mut().step();
+    let v = pool.run_until(rx.next());
+    assert_eq!(v, Some(0));
+}
+
+#[test]
+fn session_router_routes_and_expires_sessions() {
+    let mut clock = MockClock::new(Instant::now());
+    let (mut tx, rx) = channel(8);
+    let children = Rc::new(RefCell::new(Vec::new()));
+    let created = children.clone();
+    let router = SessionRouter::new(
+        clock.handle(),
+        Duration::new(10, 0),
+        rx,
+        |v: &(u32, char)| v.0,
+        move |k: &u32| {
+            let (child_tx, child_rx) = channel(8);
+            created.borrow_mut().push((*k, child_rx));
+            child_tx
+        },
+    );
+
+    let mut pool = LocalPool::new();
+
+    pool.spawner().spawn_local(router.map(drop)).unwrap();
+
+    let mut items = futures::stream::iter(vec![(1, 'a'), (2, 'b'), (1, 'c')]).map(Ok);
+    pool.run_until(tx.send_all(&mut items)).unwrap();
+    pool.run_until_stalled();
+    let mut sessions: Vec<(u32, Receiver<(u32, char)>)> = children.borrow_mut().drain(..).collect();
+    assert_eq!(sessions.iter().map(|s| s.0).collect::<Vec<_>>(), vec![1, 2]);
+    let (_, ref mut first) = sessions[0];
+    assert_eq!(first.try_recv().ok(), Some((1, 'a')));
+    assert_eq!(first.try_recv().ok(), Some((1, 'c')));
+
+    // Idle sessions are closed, and a new message starts a fresh child.
+    clock.advance(Duration::new(10, 0));
+    pool.run_until_stalled();
+    assert_eq!(pool.run_until(sessions[0].1.next()), None);
+    assert_eq!(pool.run_until(sessions[1].1.next()), Some((2, 'b')));
+    assert_eq!(pool.run_until(sessions[1].1.next()), None);
+
+    pool.run_until(tx.send((1, 'd'))).unwrap();
+    pool.run_until_stalled();
+    assert_eq!(children.borrow().len(), 1);
+}
+
+#[test]
+fn session_router_hands_back_undelivered_messages() {
+    let clock = MockClock::new(Instant::now());
+    let (mut tx, rx) = channel(8);
+    let children = Rc::new(RefCell::new(Vec::new()));
+    let torn_down = Rc::new(RefCell::new(Vec::new()));
+    let undelivered = Rc::new(RefCell::new(Vec::new()));
+    let (created, ended, returned) = (children.clone(), torn_down.clone(), undelivered.clone());
+    let router = SessionRouter::new(
+        clock.handle(),
+        Duration::new(10, 0),
+        rx,
+        |v: &(u32, char)| v.0,
+        move |_: &u32| {
+            let (child_tx, child_rx) = channel::<(u32, char)>(8);
+            created.borrow_mut().push(child_rx);
+            child_tx
+        },
+    )
+    .on_teardown(move |k| ended.borrow_mut().push(*k))
+    .on_undelivered(move |_, v| returned.borrow_mut().push(v));
+
+    let mut pool = LocalPool::new();
+
+    pool.spawner().spawn_local(router.map(drop)).unwrap();
+
+    pool.run_until(tx.send((1, 'a'))).unwrap();
+    pool.run_until_stalled();
+    // The child goes away without taking its message.
+    children.borrow_mut().clear();
+    pool.run_until(tx.send((1, 'b'))).unwrap();
+    pool.run_until_stalled();
+
+    assert_eq!(*undelivered.borrow(), vec![(1, 'b')]);
+    assert_eq!(*torn_down.borrow(), vec![1]);
+}
+
+#[test]
+fn tokio_channels_and_spawn_local() {
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let local = tokio::task::LocalSet::new();
+    let (tx1, rx1) = tokio::sync::mpsc::channel(1);
+    let (tx2, mut rx2) = tokio::sync::mpsc::channel(1);
+    let mut builder = Builder::new();
+    let out = builder.new_tokio_output(tx2, OutputOptions::new());
+    builder.new_tokio_input(
+        rx1,
+        InputOptions::new(),
+        |s: &mut Passthrough, v: i32| s.on_input(v),
+        |s: &mut Passthrough| s.on_input_end(),
+    );
+    builder.finish(Passthrough { output: out }).spawn_local(&local);
+
+    let out = local.block_on(&runtime, async move {
+        let mut out = Vec::new();
+        for i in 0..3 {
+            tx1.send(i).await.unwrap();
+            out.push(rx2.recv().await.unwrap());
+        }
+        out
+    });
+    assert_eq!(out, vec![0, 1, 2]);
+}
+
+#[test]
+fn stream_input() {
+    let (tx, rx) = channel(4);
+    let (mut tick_tx, tick_rx) = channel(4);
+    let mut builder = Builder::new();
+    let out = builder.new_output::<i32>(tx);
+    builder.new_input(
+        futures::stream::select(futures::stream::iter(vec![1, 2]), tick_rx.map(|v: i32| -v)),
+        |s: &mut Passthrough, v: i32| s.on_input(v),
+        |s: &mut Passthrough| s.on_input_end(),
+    );
+    let c = builder.finish(Passthrough { output: out });
+
+    let mut pool = LocalPool::new();
+
+    pool.spawner().spawn_local(c.map(drop)).unwrap();
+
+    pool.run_until(tick_tx.send(3)).unwrap();
+    let mut out = pool.run_until(rx.take(3).collect::<Vec<_>>());
+    out.sort();
+    assert_eq!(out, vec![-3, 1, 2]);
+}
+
+struct Doubler {
+    replies: Output<i32>,
+}
+
+impl Doubler {
+    fn new(end: LinkEnd<i32, u32>) -> Agent<Doubler> {
+        let mut builder = Builder::new();
+        let replies = builder.new_link(
+            end,
+            |s: &mut Doubler, v: u32| s.replies.send(v as i32 * 2),
+            |_: &mut Doubler| (),
+        );
+        builder.finish(Doubler { replies })
+    }
+}
+
+#[test]
+fn duplex_link() {
+    let (client, server) = duplex::<u32, i32>(1);
+    let c = Doubler::new(server);
+    let (mut requests, mut replies) = client.into_parts();
+
+    let mut pool = LocalPool::new();
+
+    pool.spawner().spawn_local(c.map(drop)).unwrap();
+
+    for i in 1..4 {
+        pool.run_until(requests.send(i)).unwrap();
+        assert_eq!(pool.run_until(replies.next()), Some(i as i32 * 2));
+    }
+}
+
+#[test]
+fn quorum_broadcast() {
+    let mut clock = MockClock::new(Instant::now());
+    let (mut tx, rx) = channel(4);
+    let (delivered_tx, mut delivered) = channel(4);
+    let mut ends = Vec::new();
+    let mut subscribers = Vec::new();
+    for _ in 0..3 {
+        let (end, subscriber) = duplex::<(u64, char), u64>(4);
+        ends.push(end);
+        subscribers.push(subscriber.into_parts());
+    }
+    let failures = Rc::new(RefCell::new(Vec::new()));
+    let failed = failures.clone();
+    let c = QuorumBroadcast::new(
+        clock.handle(),
+        rx,
+        ends,
+        delivered_tx,
+        QuorumOptions::new(2).max_retries(1),
+        move |id, v| failed.borrow_mut().push((id, v)),
+    );
+
+    let mut pool = LocalPool::new();
+
+    pool.spawner().spawn_local(c.map(drop)).unwrap();
+
+    pool.run_until(tx.send('a')).unwrap();
+    for s in subscribers.iter_mut() {
+        assert_eq!(pool.run_until(s.1.next()), Some((0, 'a')));
+    }
+    pool.run_until(subscribers[0].0.send(0)).unwrap();
+    assert_eq!(try_next(&mut pool, &mut delivered), None);
+    pool.run_until(subscribers[2].0.send(0)).unwrap();
+    assert_eq!(pool.run_until(delivered.next()), Some(0));
+
+    // Only one subscriber acknowledges the second message: it is resent to
+    // the others, then given up.
+    pool.run_until(tx.send('b')).unwrap();
+    for s in subscribers.iter_mut() {
+        assert_eq!(pool.run_until(s.1.next()), Some((1, 'b')));
+    }
+    pool.run_until(subscribers[1].0.send(1)).unwrap();
+    pool.run_until_stalled();
+    clock.advance(Duration::new(1, 0));
+    assert_eq!(pool.run_until(subscribers[0].1.next()), Some((1, 'b')));
+    assert_eq!(pool.run_until(subscribers[2].1.next()), Some((1, 'b')));
+    assert_eq!(try_next(&mut pool, &mut subscribers[1].1), None);
+    clock.advance(Duration::new(1, 0));
+    pool.run_until_stalled();
+    assert_eq!(*failures.borrow(), vec![(1, 'b')]);
+    assert_eq!(try_next(&mut pool, &mut delivered), None);
+}
+
+#[test]
+fn quorum_broadcast_finishes_with_its_input() {
+    let mut clock = MockClock::new(Instant::now());
+    let (mut tx, rx) = channel(4);
+    let (delivered_tx, delivered) = channel(4);
+    let mut ends = Vec::new();
+    let mut subscribers = Vec::new();
+    for _ in 0..2 {
+        let (end, subscriber) = duplex::<(u64, char), u64>(4);
+        ends.push(end);
+        subscribers.push(subscriber.into_parts());
+    }
+    let c = QuorumBroadcast::new(
+        clock.handle(),
+        rx,
+        ends,
+        delivered_tx,
+        QuorumOptions::new(2),
+        |_, _| (),
+    );
+
+    let done = Rc::new(RefCell::new(false));
+    let d = done.clone();
+    let mut pool = LocalPool::new();
+
+    pool.spawner().spawn_local(c.map(move |_| *d.borrow_mut() = true)).unwrap();
+
+    pool.run_until(tx.send('a')).unwrap();
+    drop(tx);
+    for s in subscribers.iter_mut() {
+        assert_eq!(pool.run_until(s.1.next()), Some((0, 'a')));
+        pool.run_until(s.0.send(0)).unwrap();
+    }
+    drop(subscribers);
+    pool.run_until_stalled();
+    assert!(!*done.borrow());
+
+    // The retry timer finds nothing left to do and stops.
+    clock.advance(Duration::new(1, 0));
+    assert_eq!(pool.run_until(delivered.collect::<Vec<_>>()), vec![0]);
+    assert!(*done.borrow());
+}
+
+struct Counter {
+    output: Output<i32>,
+    count: i32,
+    ended: i32,
+}
+
+#[test]
+fn on_stop_runs_when_inputs_end() {
+    let (mut tx1, rx1) = channel(1);
+    let (tx2, rx2) = channel(4);
+    let mut builder = Builder::new();
+    let output = builder.new_output::<i32>(tx2);
+    builder.new_input(
+        rx1,
+        |s: &mut Counter, _: i32| s.count += 1,
+        |s: &mut Counter| s.ended += 1,
+    );
+    builder.on_stop(|s: &mut Counter| {
+        let total = s.count * 10 + s.ended;
+        s.output.send(total)
+    });
+    let c = builder.finish(Counter {
+        output,
+        count: 0,
+        ended: 0,
+    });
+
+    tx1.try_send(1).unwrap();
+    tx1.try_send(2).unwrap();
+    drop(tx1);
+
+    let mut pool = LocalPool::new();
+
+    assert_eq!(pool.run_until(c), Ok(()));
+    assert_eq!(pool.run_until(rx2.collect::<Vec<_>>()), vec![21]);
+}
+
+#[test]
+fn shutdown_handle_stops_agent() {
+    let (mut tx1, rx1) = channel(4);
+    let (tx2, rx2) = channel(0);
+    let mut builder = Builder::new();
+    let output = builder.new_output::<i32>(tx2);
+    builder.new_input(
+        rx1,
+        |s: &mut Counter, v: i32| {
+            s.count += 1;
+            s.output.send(v)
+        },
+        |s: &mut Counter| s.ended += 1,
+    );
+    builder.on_stop(|s: &mut Counter| {
+        let total = s.count * 10 + s.ended;
+        s.output.send(total)
+    });
+    let c = builder.finish(Counter {
+        output,
+        count: 0,
+        ended: 0,
+    });
+    let handle = c.shutdown_handle();
+
+    let done = Rc::new(RefCell::new(false));
+    let d = done.clone();
+    let mut pool = LocalPool::new();
+    pool.spawner()
+        .spawn_local(c.map(move |_| *d.borrow_mut() = true))
+        .unwrap();
+
+    for v in 1..4 {
+        tx1.try_send(v).unwrap();
+    }
+    pool.run_until_stalled();
+    handle.shutdown();
+    pool.run_until_stalled();
+    // The output is full, so the message sent by on_stop holds the agent up.
+    assert!(!*done.borrow());
+
+    // The input is still open, but the agent stops taking from it.
+    assert_eq!(pool.run_until(rx2.collect::<Vec<_>>()), vec![1, 10]);
+    assert!(*done.borrow());
+    assert!(tx1.try_send(4).is_err());
+}
+
+#[test]
+fn encoded_output() {
+    let (mut tx1, rx1) = channel(4);
+    let (tx2, rx2) = channel(4);
+    let mut builder = Builder::new();
+    let mut last = 0;
+    let output = builder.new_encoded_output(tx2, OutputOptions::new(), move |v: i32| {
+        let delta = v - last;
+        last = v;
+        delta
+    });
+    builder.new_input(
+        rx1,
+        |s: &mut Passthrough, v| s.output.send(v),
+        |_: &mut Passthrough| (),
+    );
+    let c = builder.finish(Passthrough { output });
+
+    let mut pool = LocalPool::new();
+    pool.spawner().spawn_local(c.map(drop)).unwrap();
+
+    let mut items = futures::stream::iter(vec![100, 101, 103, 103]).map(Ok);
+    pool.run_until(tx1.send_all(&mut items)).unwrap();
+    let deltas = pool.run_until(rx2.take(4).collect::<Vec<_>>());
+    assert_eq!(deltas, vec![100, 1, 2, 0]);
+
+    let mut total = 0;
+    let decoded: Vec<i32> = deltas
+        .into_iter()
+        .map(|d| {
+            total += d;
+            total
+        })
+        .collect();
+    assert_eq!(decoded, vec![100, 101, 103, 103]);
+}
+
+#[test]
+fn encoded_link() {
+    let (a, b) = duplex::<i32, i32>(4);
+    let (mut tx1, rx1) = channel(4);
+    let (tx2, rx2) = channel(4);
+
+    // Sends deltas over the link.
+    let mut builder = Builder::new();
+    let mut last = 0;
+    let output = builder.new_encoded_link(
+        a,
+        move |v: i32| {
+            let delta = v - last;
+            last = v;
+            delta
+        },
+        |v: i32| v,
+        |_: &mut Passthrough, _: i32| (),
+        |_: &mut Passthrough| (),
+    );
+    builder.new_input(
+        rx1,
+        |s: &mut Passthrough, v| s.output.send(v),
+        |_: &mut Passthrough| (),
+    );
+    let sender = builder.finish(Passthrough { output });
+
+    // Adds them back up on the other end.
+    let mut builder = Builder::new();
+    let output = builder.new_output(tx2);
+    let mut total = 0;
+    builder.new_encoded_link(
+        b,
+        |v: i32| v,
+        move |d: i32| {
+            total += d;
+            total
+        },
+        |s: &mut Passthrough, v: i32| s.output.send(v),
+        |_: &mut Passthrough| (),
+    );
+    let receiver = builder.finish(Passthrough { output });
+
+    let mut pool = LocalPool::new();
+    pool.spawner().spawn_local(sender.map(drop)).unwrap();
+    pool.spawner().spawn_local(receiver.map(drop)).unwrap();
+
+    let mut items = futures::stream::iter(vec![100, 101, 103, 103, 90]).map(Ok);
+    pool.run_until(tx1.send_all(&mut items)).unwrap();
+    let out = pool.run_until(rx2.take(5).collect::<Vec<_>>());
+    assert_eq!(out, vec![100, 101, 103, 103, 90]);
+}
+
+struct Looped {
+    output: Rc<RefCell<Option<Output<i32>>>>,
+}
+
+#[test]
+#[should_panic(expected = "re-entrantly")]
+fn reentrant_send_from_encoder() {
+    let (mut tx1, rx1) = channel(4);
+    let (tx2, _rx2) = channel(4);
+    let mut builder = Builder::new();
+    let slot: Rc<RefCell<Option<Output<i32>>>> = Rc::new(RefCell::new(None));
+    let looped = slot.clone();
+    // The encoder runs while the agent polls the output and sends to it again.
+    let output = builder.new_encoded_output(tx2, OutputOptions::new().deferred(), move |v: i32| {
+        if let Some(o) = looped.borrow_mut().as_mut() {
+            o.send(v + 1);
+        }
+        v
+    });
+    *slot.borrow_mut() = Some(output);
+    builder.new_input(
+        rx1,
+        |s: &mut Looped, v| s.output.borrow_mut().as_mut().unwrap().send(v),
+        |_: &mut Looped| (),
+    );
+    let c = builder.finish(Looped { output: slot });
+
+    tx1.try_send(1).unwrap();
+    let mut pool = LocalPool::new();
+    pool.spawner().spawn_local(c.map(drop)).unwrap();
+    pool.run_until_stalled();
+}
+
+#[test]
+fn drains_outputs_before_finishing() {
+    let (mut tx1, rx1) = channel(1);
+    let (tx2, rx2) = channel(0);
+    let mut builder = Builder::new();
+    let output = builder.new_output::<i32>(tx2);
+    builder.new_input(
+        rx1,
+        |s: &mut Passthrough, v: i32| {
+            for i in 0..3 {
+                s.output.send(v + i);
+            }
+        },
+        |_: &mut Passthrough| (),
+    );
+    let c = builder.finish(Passthrough { output });
+
+    let done = Rc::new(RefCell::new(false));
+    let d = done.clone();
+    let mut pool = LocalPool::new();
+    pool.spawner()
+        .spawn_local(c.map(move |_| *d.borrow_mut() = true))
+        .unwrap();
+
+    tx1.try_send(10).unwrap();
+    drop(tx1);
+    pool.run_until_stalled();
+    // The input has ended, but two messages are still buffered.
+    assert!(!*done.borrow());
+
+    assert_eq!(pool.run_until(rx2.collect::<Vec<_>>()), vec![10, 11, 12]);
+    assert!(*done.borrow());
+}
+
+#[derive(Debug, PartialEq)]
+enum ParseError {
+    Negative(i32),
+    Agent(AgentError),
+}
+
+impl From<AgentError> for ParseError {
+    fn from(e: AgentError) -> ParseError {
+        ParseError::Agent(e)
+    }
+}
+
+struct Parser {
+    output: Output<i32>,
+    error: Option<ParseError>,
+}
+
+#[test]
+fn fallible_agent_reports_handler_error() {
+    let (mut tx1, rx1) = channel(4);
+    let (tx2, mut rx2) = channel(4);
+    let mut builder = Builder::new();
+    let output = builder.new_output(tx2);
+    builder.new_input(
+        rx1,
+        |s: &mut Parser, v: i32| {
+            if v < 0 {
+                s.error = Some(ParseError::Negative(v));
+            } else {
+                s.output.send(v);
+            }
+        },
+        |_: &mut Parser| (),
+    );
+    let c = builder.finish_fallible(Parser { output, error: None }, |s: &mut Parser| {
+        s.error.take()
+    });
+
+    tx1.try_send(1).unwrap();
+    tx1.try_send(-2).unwrap();
+
+    let result = LocalPool::new().run_until(c);
+    assert_eq!(result, Err(ParseError::Negative(-2)));
+    assert_eq!(rx2.try_recv().ok(), Some(1));
+}
+
+#[test]
+fn fallible_agent_stops_at_failing_handler() {
+    let (mut tx1, rx1) = channel(4);
+    let (mut tx2, rx2) = channel(4);
+    let (tx3, mut rx3) = channel(4);
+    let mut builder = Builder::new();
+    let output = builder.new_output(tx3);
+    for rx in [rx1, rx2] {
+        builder.new_input(
+            rx,
+            |s: &mut Parser, v: i32| {
+                if v < 0 {
+                    s.error = Some(ParseError::Negative(v));
+                } else {
+                    s.output.send(v);
+                }
+            },
+            |_: &mut Parser| (),
+        );
+    }
+    let c = builder.finish_fallible(Parser { output, error: None }, |s: &mut Parser| {
+        s.error.take()
+    });
+
+    // Both inputs have a message for the same poll.
+    tx1.try_send(-1).unwrap();
+    tx2.try_send(2).unwrap();
+
+    let result = LocalPool::new().run_until(c);
+    assert_eq!(result, Err(ParseError::Negative(-1)));
+    assert_eq!(rx3.try_recv().ok(), None);
+}
+
+struct Fallback {
+    primary: Output<i32>,
+    fallback: Output<i32>,
+}
+
+#[test]
+fn fallible_output_hands_back_rejected_messages() {
+    let (mut tx1, rx1) = channel(4);
+    let (tx2, rx2) = channel(4);
+    let (tx3, mut rx3) = channel(4);
+    let mut builder = Builder::new();
+    let primary = builder.new_fallible_output(tx2, OutputOptions::new(), |s: &mut Fallback, v| {
+        s.fallback.send(v)
+    });
+    let fallback = builder.new_output(tx3);
+    builder.new_input(
+        rx1,
+        |s: &mut Fallback, v: i32| s.primary.send(v),
+        |_: &mut Fallback| (),
+    );
+    let c = builder.finish_v2(Fallback { primary, fallback });
+    drop(rx2);
+
+    tx1.try_send(1).unwrap();
+    tx1.try_send(2).unwrap();
+    drop(tx1);
+
+    assert_eq!(LocalPool::new().run_until(c), Ok(()));
+    assert_eq!(rx3.try_recv().ok(), Some(1));
+    assert_eq!(rx3.try_recv().ok(), Some(2));
+}
+
+fn bounded_agent(
+    receiver: Receiver<i32>,
+    sender: Sender<i32>,
+    policy: BackpressurePolicy,
+) -> Agent<Passthrough, AgentError> {
+    let mut builder = Builder::new();
+    let options = OutputOptions::new().name("out").backpressure(policy);
+    let output = builder.new_output_with_options(sender, options);
+    builder.new_input(
+        receiver,
+        |s: &mut Passthrough, v: i32| {
+            for i in 0..5 {
+                s.output.send(v + i);
+            }
+        },
+        |_: &mut Passthrough| (),
+    );
+    builder.finish_v2(Passthrough { output })
+}
+
+#[test]
+fn backpressure_policies() {
+    let cases = vec![
+        (BackpressurePolicy::Unbounded, vec![1, 2, 3, 4, 5]),
+        (BackpressurePolicy::DropOldest(2), vec![1, 4, 5]),
+        (BackpressurePolicy::DropNewest(2), vec![1, 2, 3]),
+    ];
+    for (policy, expected) in cases {
+        let (mut tx1, rx1) = channel(1);
+        let (tx2, rx2) = channel(0);
+        let c = bounded_agent(rx1, tx2, policy);
+
+        let mut pool = LocalPool::new();
+        pool.spawner().spawn_local(c.map(drop)).unwrap();
+
+        tx1.try_send(1).unwrap();
+        drop(tx1);
+        // The first message takes the channel's only slot, the rest queue up
+        // in the output.
+        pool.run_until_stalled();
+        assert_eq!(pool.run_until(rx2.collect::<Vec<_>>()), expected);
+    }
+
+    let (mut tx1, rx1) = channel(1);
+    let (tx2, _rx2) = channel(0);
+    let c = bounded_agent(rx1, tx2, BackpressurePolicy::FailSend(2));
+    tx1.try_send(1).unwrap();
+
+    let result = LocalPool::new().run_until(c);
+    assert_eq!(result, Err(AgentError::OutputOverflow(Some("out".to_string()))));
+}
+
+struct Shedder {
+    output: Output<i32>,
+    shed: Rc<RefCell<Vec<i32>>>,
+}
+
+#[test]
+fn ordered_outputs_drop_oldest() {
+    let (mut tx1, rx1) = channel(1);
+    let (tx2, rx2) = channel(0);
+    let mut builder = Builder::new();
+    builder.set_ordered_outputs(true);
+    let options = OutputOptions::new().backpressure(BackpressurePolicy::DropOldest(1));
+    let first = builder.new_output_with_options(tx2.clone(), options);
+    let second = builder.new_output(tx2);
+    builder.new_input(
+        rx1,
+        |s: &mut Splitter, v: i32| {
+            s.second.send(v + 99);
+            s.second.send(v + 100);
+            s.first.send(v + 1);
+            s.first.send(v + 2);
+        },
+        |_: &mut Splitter| (),
+    );
+    let c = builder.finish(Splitter { first, second });
+
+    let mut pool = LocalPool::new();
+    pool.spawner().spawn_local(c.map(drop)).unwrap();
+    tx1.try_send(0).unwrap();
+    drop(tx1);
+
+    // Dropping 1 doesn't take the place of 100 in the send order.
+    assert_eq!(pool.run_until(rx2.collect::<Vec<_>>()), vec![99, 100, 2]);
+}
+
+#[test]
+fn ordered_outputs_lose_a_receiver() {
+    let (mut tx1, rx1) = channel(1);
+    let (tx2, rx2) = channel(0);
+    let (tx3, rx3) = channel(0);
+    let mut builder = Builder::new();
+    builder.set_ordered_outputs(true);
+    let first = builder.new_output(tx2);
+    let second = builder.new_output(tx3);
+    builder.new_input(
+        rx1,
+        |s: &mut Splitter, v: i32| {
+            s.first.send(v + 1);
+            s.second.send(v + 100);
+            s.first.send(v + 2);
+            s.second.send(v + 101);
+            s.first.send(v + 3);
+        },
+        |_: &mut Splitter| (),
+    );
+    let c = builder.finish(Splitter { first, second });
+
+    let mut pool = LocalPool::new();
+    pool.spawner().spawn_local(c.map(drop)).unwrap();
+    tx1.try_send(0).unwrap();
+    drop(tx1);
+    pool.run_until_stalled();
+
+    // The messages left for the first output go with it, without holding up
+    // the second one.
+    drop(rx2);
+    assert_eq!(pool.run_until(rx3.collect::<Vec<_>>()), vec![100, 101]);
+}
+
+#[test]
+fn bounded_output_reports_overflow() {
+    let (mut tx1, rx1) = channel(1);
+    let (tx2, rx2) = channel(0);
+    let mut builder = Builder::new();
+    let options = OutputOptions::new().backpressure(BackpressurePolicy::DropNewest(2));
+    let output = builder.new_bounded_output(tx2, options, |s: &mut Shedder, v| {
+        s.shed.borrow_mut().push(v)
+    });
+    builder.new_input(
+        rx1,
+        |s: &mut Shedder, v: i32| {
+            for i in 0..5 {
+                s.output.send(v + i);
+            }
+        },
+        |_: &mut Shedder| (),
+    );
+    let shed = Rc::new(RefCell::new(Vec::new()));
+    let c = builder.finish(Shedder {
+        output,
+        shed: shed.clone(),
+    });
+
+    let mut pool = LocalPool::new();
+    pool.spawner().spawn_local(c.map(drop)).unwrap();
+
+    tx1.try_send(1).unwrap();
+    drop(tx1);
+    pool.run_until_stalled();
+    assert_eq!(*shed.borrow(), vec![4, 5]);
+    assert_eq!(pool.run_until(rx2.collect::<Vec<_>>()), vec![1, 2, 3]);
+}
+
+#[test]
+fn bounded_output_keeps_its_policy() {
+    let (mut tx1, rx1) = channel(1);
+    let (tx2, rx2) = channel(0);
+    let mut builder = Builder::new();
+    let options = OutputOptions::new().backpressure(BackpressurePolicy::DropOldest(2));
+    let output = builder.new_bounded_output(tx2, options, |s: &mut Shedder, v| {
+        s.shed.borrow_mut().push(v)
+    });
+    builder.new_input(
+        rx1,
+        |s: &mut Shedder, v: i32| {
+            for i in 0..5 {
+                s.output.send(v + i);
+            }
+        },
+        |_: &mut Shedder| (),
+    );
+    let shed = Rc::new(RefCell::new(Vec::new()));
+    let c = builder.finish(Shedder {
+        output,
+        shed: shed.clone(),
+    });
+
+    let mut pool = LocalPool::new();
+    pool.spawner().spawn_local(c.map(drop)).unwrap();
+
+    tx1.try_send(1).unwrap();
+    drop(tx1);
+    pool.run_until_stalled();
+    assert_eq!(*shed.borrow(), vec![2, 3]);
+    assert_eq!(pool.run_until(rx2.collect::<Vec<_>>()), vec![1, 4, 5]);
+}
+
+#[test]
+#[should_panic(expected = "bounded backpressure policy")]
+fn bounded_output_needs_a_bound() {
+    let (tx, _rx) = channel::<i32>(1);
+    let mut builder = Builder::new();
+    builder.new_bounded_output(tx, OutputOptions::new(), |_: &mut (), _| ());
+}
+
+struct Reporter {
+    events: Output<i32>,
+    logs: Output<i32>,
+}
+
+#[test]
+fn shutdown_timeout_reports_unflushed_outputs() {
+    let mut clock = MockClock::new(Instant::now());
+    let (_tx1, rx1) = channel::<i32>(1);
+    let (tx2, mut events_rx) = channel(0);
+    let (tx3, _logs_rx) = channel(0);
+    let mut builder = Builder::new();
+    let events = builder.new_output_with_options(tx2, OutputOptions::new().name("events"));
+    let logs = builder.new_output_with_options(tx3, OutputOptions::new().name("logs").best_effort());
+    builder.new_input(rx1, |_: &mut Reporter, _| (), |_: &mut Reporter| ());
+    builder.on_stop(|s: &mut Reporter| {
+        for v in 1..3 {
+            s.events.send(v);
+            s.logs.send(v);
+        }
+    });
+    builder.set_shutdown_timeout(clock.handle(), Duration::new(1, 0));
+    let c = builder.finish_v2(Reporter { events, logs });
+    let handle = c.shutdown_handle();
+
+    let result = Rc::new(RefCell::new(None));
+    let r = result.clone();
+    let mut pool = LocalPool::new();
+    pool.spawner()
+        .spawn_local(c.map(move |v| *r.borrow_mut() = Some(v)))
+        .unwrap();
+    pool.run_until_stalled();
+
+    handle.shutdown();
+    assert_eq!(pool.run_until(events_rx.next()), Some(1));
+    assert_eq!(pool.run_until(events_rx.next()), Some(2));
+    assert_eq!(*result.borrow(), None);
+
+    clock.advance(Duration::new(1, 0));
+    pool.run_until_stalled();
+    assert_eq!(
+        *result.borrow(),
+        Some(Err(AgentError::Unflushed(vec![(Some("logs".to_string()), 1)])))
+    );
+}
+
+struct Squarer;
+
+struct Caller {
+    server: Output<Request<i32, i32>>,
+    replies: Rc<RefCell<Vec<futures::channel::oneshot::Receiver<i32>>>>,
+}
+
+#[test]
+fn ask_and_respond() {
+    let (requests_tx, requests_rx) = channel(4);
+    let mut builder = Builder::new();
+    builder.new_request_input(
+        requests_rx,
+        |_: &mut Squarer, v: i32, r: Responder<i32>| r.respond(v * v),
+        |_: &mut Squarer| (),
+    );
+    let server = builder.finish(Squarer);
+
+    let (mut tx, rx) = channel(4);
+    let mut builder = Builder::new();
+    let output = builder.new_output(requests_tx.clone());
+    builder.new_input(
+        rx,
+        |s: &mut Caller, v: i32| {
+            let reply = s.server.ask(v);
+            s.replies.borrow_mut().push(reply)
+        },
+        |_: &mut Caller| (),
+    );
+    let replies = Rc::new(RefCell::new(Vec::new()));
+    let caller = builder.finish(Caller {
+        server: output,
+        replies: replies.clone(),
+    });
+
+    let mut pool = LocalPool::new();
+    pool.spawner().spawn_local(server.map(drop)).unwrap();
+    pool.spawner().spawn_local(caller.map(drop)).unwrap();
+
+    pool.run_until(tx.send(3)).unwrap();
+    pool.run_until_stalled();
+    let reply = replies.borrow_mut().pop().unwrap();
+    assert_eq!(pool.run_until(reply), Ok(9));
+
+    let (responder, reply) = Responder::pair();
+    pool.run_until(requests_tx.clone().send((4, responder))).unwrap();
+    assert_eq!(pool.run_until(reply), Ok(16));
+}
+
+struct Deferrer {
+    output: Output<i32>,
+    mailbox: Address<i32>,
+}
+
+#[test]
+fn self_mailbox() {
+    let (mut tx1, rx1) = channel(4);
+    let (tx2, rx2) = channel(8);
+    let mut builder = Builder::new();
+    let output = builder.new_output(tx2);
+    builder.new_input(
+        rx1,
+        |s: &mut Deferrer, v: i32| {
+            s.output.send(v);
+            s.mailbox.send(v * 10).unwrap();
+        },
+        |_: &mut Deferrer| (),
+    );
+    let mailbox = builder.self_mailbox(|s: &mut Deferrer, v: i32| s.output.send(v));
+    let c = builder.finish(Deferrer { output, mailbox });
+
+    tx1.try_send(1).unwrap();
+    tx1.try_send(2).unwrap();
+    drop(tx1);
+
+    let mut pool = LocalPool::new();
+
+    // The agent finishes even though its state still holds the address.
+    assert_eq!(pool.run_until(c), Ok(()));
+    let mut received = pool.run_until(rx2.collect::<Vec<_>>());
+    received.sort();
+    assert_eq!(received, vec![1, 2, 10, 20]);
+}
+
+#[test]
+fn deadline_stops_agent() {
+    let start = Instant::now();
+    let mut clock = MockClock::new(start);
+    let (_tx1, rx1) = channel::<i32>(1);
+    let (tx2, rx2) = channel(4);
+    let mut builder = Builder::new();
+    let output = builder.new_output::<i32>(tx2);
+    builder.new_input(rx1, |s: &mut Counter, _| s.count += 1, |s: &mut Counter| s.ended += 1);
+    builder.on_stop(|s: &mut Counter| {
+        let total = s.count * 10 + s.ended;
+        s.output.send(total)
+    });
+    let deadline = start + Duration::new(5, 0);
+    let c = builder
+        .finish_v2(Counter {
+            output,
+            count: 0,
+            ended: 0,
+        })
+        .with_deadline(clock.handle(), deadline);
+
+    let result = Rc::new(RefCell::new(None));
+    let r = result.clone();
+    let mut pool = LocalPool::new();
+    pool.spawner()
+        .spawn_local(c.map(move |v| *r.borrow_mut() = Some(v)))
+        .unwrap();
+    pool.run_until_stalled();
+
+    clock.advance(Duration::new(4, 0));
+    pool.run_until_stalled();
+    assert_eq!(*result.borrow(), None);
+
+    clock.advance(Duration::new(1, 0));
+    pool.run_until_stalled();
+    assert_eq!(*result.borrow(), Some(Err(AgentError::Timeout)));
+    assert_eq!(pool.run_until(rx2.collect::<Vec<_>>()), vec![0]);
+}
+
+#[test]
+fn registry_lookup() {
+    let (tx, rx) = channel(4);
+    let mut builder = Builder::new();
+    let output = builder.new_output(tx);
+    let address = builder.new_mailbox(
+        |s: &mut Passthrough, v: i32| s.output.send(v),
+        |_: &mut Passthrough| (),
+    );
+    let c = builder.finish(Passthrough { output });
+
+    let registry = AgentRegistry::new();
+    registry.register("passthrough", address);
+    assert!(registry.lookup::<String>("passthrough").is_none());
+    assert!(registry.lookup::<i32>("missing").is_none());
+
+    let mut pool = LocalPool::new();
+    pool.spawner().spawn_local(c.map(drop)).unwrap();
+
+    let address = registry.lookup::<i32>("passthrough").unwrap();
+    address.send(7).unwrap();
+    drop(address);
+    registry.unregister("passthrough");
+    // With every address gone, the mailbox ends and so does the agent.
+    assert_eq!(pool.run_until(rx.collect::<Vec<_>>()), vec![7]);
+}
+
+struct RouteReader {
+    output: Output<(u64, i32)>,
+}
+
+#[test]
+fn shared_view() {
+    let mut publisher = ViewPublisher::new(1);
+    let view = publisher.view();
+    let (tx, mut rx) = channel(4);
+    let mut builder = Builder::new();
+    let output = builder.new_output(tx);
+    builder.new_input(
+        view.clone(),
+        |s: &mut RouteReader, (epoch, v): (u64, std::sync::Arc<i32>)| s.output.send((epoch, *v)),
+        |_: &mut RouteReader| (),
+    );
+    let c = builder.finish(RouteReader { output });
+
+    let mut pool = LocalPool::new();
+    pool.spawner().spawn_local(c.map(drop)).unwrap();
+    assert_eq!(pool.run_until(rx.next()), Some((0, 1)));
+
+    assert_eq!(publisher.publish(2), 1);
+    assert_eq!(publisher.publish(3), 2);
+    assert_eq!(*view.latest(), 3);
+    assert_eq!(view.epoch(), 2);
+    // Snapshots published in between are skipped.
+    assert_eq!(pool.run_until(rx.next()), Some((2, 3)));
+
+    drop(publisher);
+    assert_eq!(pool.run_until(rx.next()), None);
+}
+
+#[test]
+fn supervisor_restarts_failed_agents() {
+    let clock = MockClock::new(Instant::now());
+    let registry = AgentRegistry::new();
+    let (tx, mut rx) = channel(4);
+    let starts = Rc::new(RefCell::new(0));
+
+    let mut supervisor = Supervisor::new(clock.handle(), RestartStrategy::OneForOne)
+        .max_restarts(1, Duration::new(10, 0));
+    let r = registry.clone();
+    let s = starts.clone();
+    supervisor.add(move || {
+        *s.borrow_mut() += 1;
+        let mut builder = Builder::new();
+        let output = builder.new_output(tx.clone());
+        let address = builder.new_mailbox(
+            |s: &mut Passthrough, v: i32| {
+                assert!(v >= 0, "negative");
+                s.output.send(v)
+            },
+            |_: &mut Passthrough| (),
+        );
+        r.register("worker", address);
+        builder.finish_v2(Passthrough { output })
+    });
+
+    let result = Rc::new(RefCell::new(None));
+    let res = result.clone();
+    let mut pool = LocalPool::new();
+    pool.spawner()
+        .spawn_local(supervisor.map(move |v| *res.borrow_mut() = Some(v)))
+        .unwrap();
+
+    let worker = || registry.lookup::<i32>("worker").unwrap();
+    worker().send(1).unwrap();
+    assert_eq!(pool.run_until(rx.next()), Some(1));
+
+    worker().send(-1).unwrap();
+    pool.run_until_stalled();
+    assert_eq!(*starts.borrow(), 2);
+    worker().send(2).unwrap();
+    assert_eq!(pool.run_until(rx.next()), Some(2));
+
+    // A second failure within the window is one restart too many.
+    worker().send(-2).unwrap();
+    pool.run_until_stalled();
+    assert_eq!(*starts.borrow(), 2);
+    assert_eq!(
+        *result.borrow(),
+        Some(Err(AgentError::Panic("negative".to_string())))
+    );
+}
+
+// Reads `first` on its first start and nothing on later ones, so a restarted
+// child finishes as soon as it is polled.
+fn one_shot_child(
+    first: Receiver<i32>,
+    starts: Rc<RefCell<i32>>,
+) -> impl FnMut() -> Agent<(), AgentError> {
+    let mut first = Some(first);
+    move || {
+        *starts.borrow_mut() += 1;
+        let mut builder = Builder::new();
+        let on_item = |_: &mut (), v: i32| assert!(v >= 0, "negative");
+        match first.take() {
+            Some(rx) => builder.new_input(rx, on_item, |_: &mut ()| ()),
+            None => builder.new_input(futures::stream::empty(), on_item, |_: &mut ()| ()),
+        }
+        builder.finish_v2(())
+    }
+}
+
+#[test]
+fn supervisor_restarts_all_children() {
+    let clock = MockClock::new(Instant::now());
+    let (mut tx_a, rx_a) = channel(1);
+    let (_tx_b, rx_b) = channel(1);
+    let starts = Rc::new(RefCell::new(0));
+
+    let mut supervisor = Supervisor::new(clock.handle(), RestartStrategy::AllForOne);
+    supervisor.add(one_shot_child(rx_a, starts.clone()));
+    supervisor.add(one_shot_child(rx_b, starts.clone()));
+
+    let result = Rc::new(RefCell::new(None));
+    let res = result.clone();
+    let mut pool = LocalPool::new();
+    pool.spawner()
+        .spawn_local(supervisor.map(move |v| *res.borrow_mut() = Some(v)))
+        .unwrap();
+    pool.run_until_stalled();
+    assert_eq!(*starts.borrow(), 2);
+
+    // The second child is still running, but is restarted with the first.
+    pool.run_until(tx_a.send(-1)).unwrap();
+    pool.run_until_stalled();
+    assert_eq!(*starts.borrow(), 4);
+    assert_eq!(*result.borrow(), Some(Ok(())));
+}
+
+#[test]
+fn anti_entropy_syncs_replicas() {
+    let mut clock = MockClock::new(Instant::now());
+    let (end_a, end_b) = duplex(4);
+    let (mut writes_a, rx) = channel(4);
+    let (changes_tx, changes_a) = channel(8);
+    let a = AntiEntropyAgent::new(clock.handle(), Duration::new(1, 0), rx, end_a, changes_tx);
+    let (mut writes_b, rx) = channel(4);
+    let (changes_tx, mut changes_b) = channel(8);
+    let b = AntiEntropyAgent::new(clock.handle(), Duration::new(1, 0), rx, end_b, changes_tx);
+
+    let mut pool = LocalPool::new();
+    pool.spawner().spawn_local(a.map(drop)).unwrap();
+    pool.spawner().spawn_local(b.map(drop)).unwrap();
+
+    writes_a.try_send(("a", 1, 10)).unwrap();
+    writes_b.try_send(("b", 1, 20)).unwrap();
+    writes_b.try_send(("a", 2, 11)).unwrap();
+    pool.run_until_stalled();
+    clock.advance(Duration::new(1, 0));
+    pool.run_until_stalled();
+
+    assert_eq!(
+        pool.run_until(changes_a.take(3).collect::<Vec<_>>()),
+        vec![("a", 1, 10), ("a", 2, 11), ("b", 1, 20)]
+    );
+    assert_eq!(
+        pool.run_until(changes_b.by_ref().take(2).collect::<Vec<_>>()),
+        vec![("b", 1, 20), ("a", 2, 11)]
+    );
+    assert_eq!(try_next(&mut pool, &mut changes_b), None);
+}
+
+#[test]
+fn anti_entropy_finishes_with_its_writes() {
+    let mut clock = MockClock::new(Instant::now());
+    let (end_a, end_b) = duplex(4);
+    let (mut writes_a, rx) = channel(4);
+    let (changes_tx, changes_a) = channel(8);
+    let a = AntiEntropyAgent::new(clock.handle(), Duration::new(1, 0), rx, end_a, changes_tx);
+    let (mut writes_b, rx) = channel(4);
+    let (changes_tx, changes_b) = channel(8);
+    let b = AntiEntropyAgent::new(clock.handle(), Duration::new(1, 0), rx, end_b, changes_tx);
+
+    let mut pool = LocalPool::new();
+    pool.spawner().spawn_local(a.map(drop)).unwrap();
+    pool.spawner().spawn_local(b.map(drop)).unwrap();
+
+    writes_a.try_send(("a", 1, 10)).unwrap();
+    writes_b.try_send(("b", 1, 20)).unwrap();
+    drop(writes_a);
+    drop(writes_b);
+    pool.run_until_stalled();
+
+    // Both replicas push their stores to each other, then hang up.
+    clock.advance(Duration::new(1, 0));
+    assert_eq!(
+        pool.run_until(changes_a.collect::<Vec<_>>()),
+        vec![("a", 1, 10), ("b", 1, 20)]
+    );
+    assert_eq!(
+        pool.run_until(changes_b.collect::<Vec<_>>()),
+        vec![("b", 1, 20), ("a", 1, 10)]
+    );
+}
+
+#[test]
+fn anti_entropy_shuts_down_one_side_at_a_time() {
+    let mut clock = MockClock::new(Instant::now());
+    let (end_a, end_b) = duplex(4);
+    let (mut writes_a, rx) = channel(4);
+    let (changes_tx, mut changes_a) = channel(8);
+    let a = AntiEntropyAgent::new(clock.handle(), Duration::new(1, 0), rx, end_a, changes_tx);
+    let (mut writes_b, rx) = channel(4);
+    let (changes_tx, changes_b) = channel(8);
+    let b = AntiEntropyAgent::new(clock.handle(), Duration::new(1, 0), rx, end_b, changes_tx);
+
+    let mut pool = LocalPool::new();
+    pool.spawner().spawn_local(a.map(drop)).unwrap();
+    pool.spawner().spawn_local(b.map(drop)).unwrap();
+
+    writes_a.try_send(("a", 1, 10)).unwrap();
+    drop(writes_a);
+    pool.run_until_stalled();
+    clock.advance(Duration::new(1, 0));
+    pool.run_until_stalled();
+    assert_eq!(try_next(&mut pool, &mut changes_a), Some(("a", 1, 10)));
+
+    // A has hung up, so B's later writes no longer reach it by digest.
+    writes_b.try_send(("b", 1, 20)).unwrap();
+    for _ in 0..3 {
+        clock.advance(Duration::new(1, 0));
+        pool.run_until_stalled();
+    }
+    assert_eq!(try_next(&mut pool, &mut changes_a), None);
+
+    // B pushes them once its own writes end.
+    drop(writes_b);
+    pool.run_until_stalled();
+    clock.advance(Duration::new(1, 0));
+    assert_eq!(pool.run_until(changes_a.collect::<Vec<_>>()), vec![("b", 1, 20)]);
+    assert_eq!(
+        pool.run_until(changes_b.collect::<Vec<_>>()),
+        vec![("a", 1, 10), ("b", 1, 20)]
+    );
+}
+
+#[test]
+fn spawn_pinned_from_another_thread() {
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let pool = tokio_util::task::LocalPoolHandle::new(1);
+    let (mut tx1, rx1) = channel(1);
+    let (tx2, mut rx2) = channel(1);
+    let handle = Agent::spawn_pinned(&pool, move || Passthrough::new(rx1, tx2));
+
+    let (out, result) = runtime.block_on(async move {
+        let mut out = Vec::new();
+        for i in 0..3 {
+            tx1.send(i).await.unwrap();
+            out.push(rx2.next().await.unwrap());
+        }
+        drop(tx1);
+        (out, handle.await.unwrap())
+    });
+    assert_eq!(out, vec![0, 1, 2]);
+    assert_eq!(result, Ok(()));
+}
+
+struct Keyed {
+    output: Output<(char, u64)>,
+}
+
+#[test]
+fn ordering_oracle() {
+    let oracle = OrderingOracle::new();
+    let (mut tx1, rx1) = channel(4);
+    let (mut tx2, rx2) = channel(4);
+    let (tx3, _rx3) = channel(8);
+    let mut builder = Builder::new();
+    let output = builder.new_output(tx3);
+    for (link, rx) in [("left", rx1), ("right", rx2)] {
+        builder.new_input(
+            oracle.record(link, rx),
+            |s: &mut Keyed, v: (char, u64)| s.output.send(v),
+            |_: &mut Keyed| (),
+        );
+    }
+    let c = builder.finish(Keyed { output });
+
+    let mut pool = LocalPool::new();
+    pool.spawner().spawn_local(c.map(drop)).unwrap();
+
+    pool.run_until(tx1.send(('a', 1))).unwrap();
+    pool.run_until(tx2.send(('b', 1))).unwrap();
+    pool.run_until(tx2.send(('a', 2))).unwrap();
+    pool.run_until_stalled();
+    assert!(oracle.delivered_before(&('a', 1), &('b', 1)));
+    assert!(!oracle.delivered_before(&('a', 2), &('a', 1)));
+    assert_eq!(oracle.check_in_order_by(|&(k, seq)| (k, seq)), Ok(()));
+
+    pool.run_until(tx1.send(('b', 0))).unwrap();
+    pool.run_until_stalled();
+    assert_eq!(
+        oracle.check_in_order_by(|&(k, seq)| (k, seq)),
+        Err("key 'b': ('b', 0) delivered on left after ('b', 1) on right\n  \
+             right: ('b', 1)\n  left: ('b', 0)\n"
+            .to_string())
+    );
+}
+
+struct Fanout {
+    factory: OutputFactory,
+    consumers: Vec<Output<i32>>,
+}
+
+#[test]
+fn output_factory_adds_outputs() {
+    let (mut tx1, rx1) = channel(4);
+    let (mut tx2, rx2) = channel(4);
+    let mut builder = Builder::new();
+    let factory = builder.output_factory();
+    builder.new_input(
+        rx1,
+        |s: &mut Fanout, sender: Sender<i32>| {
+            let output = s.factory.new_output(sender);
+            s.consumers.push(output);
+        },
+        |_: &mut Fanout| (),
+    );
+    builder.new_input(
+        rx2,
+        |s: &mut Fanout, v: i32| {
+            for o in s.consumers.iter_mut() {
+                o.send(v);
+            }
+        },
+        |_: &mut Fanout| (),
+    );
+    let c = builder.finish(Fanout {
+        factory,
+        consumers: Vec::new(),
+    });
+
+    let done = Rc::new(RefCell::new(false));
+    let d = done.clone();
+    let mut pool = LocalPool::new();
+    pool.spawner()
+        .spawn_local(c.map(move |_| *d.borrow_mut() = true))
+        .unwrap();
+
+    let (a_tx, a_rx) = channel(0);
+    let (b_tx, b_rx) = channel(8);
+    tx1.try_send(a_tx).unwrap();
+    pool.run_until_stalled();
+    tx2.try_send(1).unwrap();
+    pool.run_until_stalled();
+    tx1.try_send(b_tx).unwrap();
+    pool.run_until_stalled();
+    tx2.try_send(2).unwrap();
+    tx2.try_send(3).unwrap();
+    drop(tx1);
+    drop(tx2);
+    pool.run_until_stalled();
+    // The inputs have ended, but the first consumer hasn't taken everything.
+    assert!(!*done.borrow());
+
+    assert_eq!(pool.run_until(a_rx.collect::<Vec<_>>()), vec![1, 2, 3]);
+    assert!(*done.borrow());
+    assert_eq!(pool.run_until(b_rx.collect::<Vec<_>>()), vec![2, 3]);
+}
+
+#[test]
+fn output_factory_lets_go_of_dropped_outputs() {
+    let (mut tx1, rx1) = channel(4);
+    let (mut tx2, rx2) = channel(4);
+    let mut builder = Builder::new();
+    let factory = builder.output_factory();
+    builder.new_input(
+        rx1,
+        |s: &mut Fanout, sender: Sender<i32>| {
+            let output = s.factory.new_output(sender);
+            s.consumers.push(output);
+        },
+        |_: &mut Fanout| (),
+    );
+    // A negative value disconnects every consumer.
+    builder.new_input(
+        rx2,
+        |s: &mut Fanout, v: i32| {
+            if v < 0 {
+                s.consumers.clear();
+            }
+            for o in s.consumers.iter_mut() {
+                o.send(v);
+            }
+        },
+        |_: &mut Fanout| (),
+    );
+    let c = builder.finish(Fanout {
+        factory,
+        consumers: Vec::new(),
+    });
+
+    let mut pool = LocalPool::new();
+    pool.spawner().spawn_local(c.map(drop)).unwrap();
+
+    let (a_tx, mut a_rx) = channel(4);
+    tx1.try_send(a_tx).unwrap();
+    pool.run_until_stalled();
+    tx2.try_send(1).unwrap();
+    tx2.try_send(-1).unwrap();
+    pool.run_until_stalled();
+
+    // The agent is still running, but has dropped the output and its sink.
+    assert_eq!(a_rx.try_recv().ok(), Some(1));
+    assert!(matches!(a_rx.try_recv(), Err(futures::channel::mpsc::TryRecvError::Closed)));
+}
+
+#[test]
+fn input_priorities() {
+    let (mut tx1, rx1) = channel(4);
+    let (mut tx2, rx2) = channel(4);
+    let (tx3, rx3) = channel(8);
+    let mut builder = Builder::new();
+    let output = builder.new_output(tx3);
+    builder.new_input(
+        rx1,
+        |s: &mut Passthrough, v: i32| s.on_input(v),
+        |_: &mut Passthrough| (),
+    );
+    builder.new_input_with_options(
+        rx2,
+        InputOptions::new().name("control").priority(1),
+        |s: &mut Passthrough, v: i32| s.on_input(v),
+        |_: &mut Passthrough| (),
+    );
+    let c = builder.finish(Passthrough { output });
+
+    tx1.try_send(1).unwrap();
+    tx1.try_send(2).unwrap();
+    tx2.try_send(10).unwrap();
+    tx2.try_send(20).unwrap();
+    drop(tx1);
+    drop(tx2);
+
+    let mut pool = LocalPool::new();
+    assert_eq!(pool.run_until(c), Ok(()));
+    assert_eq!(pool.run_until(rx3.collect::<Vec<_>>()), vec![10, 20, 1, 2]);
+}
+
+#[test]
+fn fair_inputs() {
+    let (tx, rx) = channel(16);
+    let mut builder = Builder::new();
+    let output = builder.new_output(tx);
+    builder.set_fair_inputs(2);
+    let mut senders = Vec::new();
+    for base in [1, 10, 100] {
+        let (mut tx, rx) = channel(4);
+        for i in 1..=3 {
+            tx.try_send(base * i).unwrap();
+        }
+        builder.new_input(
+            rx,
+            |s: &mut Passthrough, v: i32| s.on_input(v),
+            |_: &mut Passthrough| (),
+        );
+        senders.push(tx);
+    }
+    let c = builder.finish(Passthrough { output });
+    drop(senders);
+
+    let mut pool = LocalPool::new();
+    assert_eq!(pool.run_until(c), Ok(()));
+    // Two items from each input, then the second input goes first.
+    assert_eq!(
+        pool.run_until(rx.collect::<Vec<_>>()),
+        vec![1, 2, 10, 20, 100, 200, 30, 300, 3]
+    );
+}
+
+#[test]
+fn poll_budget() {
+    let (tx, rx) = channel(16);
+    let mut builder = Builder::new();
+    let output = builder.new_output(tx);
+    builder.set_poll_budget(2);
+    let mut senders = Vec::new();
+    for base in [1, 10, 100] {
+        let (mut tx, rx) = channel(4);
+        for i in 1..=2 {
+            tx.try_send(base * i).unwrap();
+        }
+        builder.new_input(
+            rx,
+            |s: &mut Passthrough, v: i32| s.on_input(v),
+            |_: &mut Passthrough| (),
+        );
+        senders.push(tx);
+    }
+    let c = builder.finish(Passthrough { output });
+
+    let done = Rc::new(RefCell::new(false));
+    let d = done.clone();
+    let mut pool = LocalPool::new();
+    pool.spawner()
+        .spawn_local(c.map(move |_| *d.borrow_mut() = true))
+        .unwrap();
+    pool.run_until_stalled();
+    drop(senders);
+    pool.run_until_stalled();
+    assert!(*done.borrow());
+
+    // Each poll picks up with the input after the last one served.
+    assert_eq!(pool.run_until(rx.collect::<Vec<_>>()), vec![1, 10, 100, 2, 20, 200]);
+}
+
+#[test]
+fn batch_input() {
+    let (mut tx1, rx1) = channel(8);
+    let (tx2, rx2) = channel(8);
+    let mut builder = Builder::new();
+    let output = builder.new_output(tx2);
+    builder.new_batch_input(
+        rx1,
+        2,
+        |s: &mut Passthrough, batch: Vec<i32>| s.on_input(batch.iter().sum()),
+        |_: &mut Passthrough| (),
+    );
+    let c = builder.finish(Passthrough { output });
+
+    for i in 1..=5 {
+        tx1.try_send(i).unwrap();
+    }
+    drop(tx1);
+
+    let mut pool = LocalPool::new();
+    assert_eq!(pool.run_until(c), Ok(()));
+    assert_eq!(pool.run_until(rx2.collect::<Vec<_>>()), vec![3, 7, 5]);
+}
+
+#[test]
+fn acked_input_commits_in_batches() {
+    let mut clock = MockClock::new(Instant::now());
+    let (mut tx1, rx1) = channel(8);
+    let (tx2, mut rx2) = channel(8);
+    let commits = Rc::new(RefCell::new(Vec::new()));
+    let committed = commits.clone();
+    let mut builder = Builder::new();
+    let output = builder.new_output(tx2);
+    builder.new_acked_input(
+        rx1,
+        CommitOptions::new(clock.handle(), Duration::new(1, 0)).batch(2),
+        |s: &mut Passthrough, v: i32| s.on_input(v),
+        move |_: &mut Passthrough, tokens: Vec<u64>| committed.borrow_mut().push(tokens),
+        |_: &mut Passthrough| (),
+    );
+    let c = builder.finish(Passthrough { output });
+
+    let done = Rc::new(RefCell::new(false));
+    let d = done.clone();
+    let mut pool = LocalPool::new();
+    pool.spawner()
+        .spawn_local(c.map(move |_| *d.borrow_mut() = true))
+        .unwrap();
+
+    for (v, token) in [(10, 0), (11, 1), (12, 2)] {
+        tx1.try_send((v, token)).unwrap();
+    }
+    pool.run_until_stalled();
+    assert_eq!(*commits.borrow(), vec![vec![0, 1]]);
+    for v in 10..13 {
+        assert_eq!(try_next(&mut pool, &mut rx2), Some(v));
+    }
+
+    // The interval commits the rest.
+    clock.advance(Duration::new(1, 0));
+    pool.run_until_stalled();
+    assert_eq!(*commits.borrow(), vec![vec![0, 1], vec![2]]);
+
+    // So does the end of the input, and then the agent finishes.
+    tx1.try_send((13, 3)).unwrap();
+    drop(tx1);
+    pool.run_until_stalled();
+    assert_eq!(*commits.borrow(), vec![vec![0, 1], vec![2], vec![3]]);
+    clock.advance(Duration::new(1, 0));
+    pool.run_until_stalled();
+    assert!(*done.borrow());
+}
+
+#[test]
+fn end_of_stream_cascades() {
+    let mut clock = MockClock::new(Instant::now());
+    let second = Duration::new(1, 0);
+    let (tx1, rx1) = channel(4);
+    let (tx2, rx2) = channel(4);
+
+    // Closes its output after three ticks, but keeps ticking.
+    let mut builder = Builder::new();
+    let output = builder.new_output(tx1);
+    builder.new_timer(clock.handle(), second, |s: &mut Counter| {
+        s.count += 1;
+        if s.count <= 3 {
+            s.output.send(s.count);
+        }
+        if s.count == 3 {
+            s.output.close();
+        }
+        TimerRun::Continue
+    });
+    let source = builder.finish(Counter {
+        output,
+        count: 0,
+        ended: 0,
+    });
+
+    let mut builder = Builder::new();
+    let output = builder.new_output(tx2);
+    builder.new_input(
+        rx1,
+        |s: &mut Passthrough, v: i32| s.on_input(v * 10),
+        |_: &mut Passthrough| (),
+    );
+    builder.new_timer(clock.handle(), second, |_: &mut Passthrough| TimerRun::Continue);
+    builder.end_with_inputs();
+    let stage = builder.finish(Passthrough { output });
+
+    let mut pool = LocalPool::new();
+    pool.spawner().spawn_local(source.map(drop)).unwrap();
+    pool.spawner().spawn_local(stage.map(drop)).unwrap();
+    for _ in 0..3 {
+        pool.run_until_stalled();
+        clock.advance(second);
+    }
+
+    assert_eq!(pool.run_until(rx2.collect::<Vec<_>>()), vec![10, 20, 30]);
+}
+
+#[test]
+fn input_with_transform() {
+    let (mut tx1, rx1) = channel(8);
+    let (tx2, rx2) = channel(8);
+    let mut builder = Builder::new();
+    let output = builder.new_output(tx2);
+    builder.new_input_with(
+        rx1,
+        |raw: &str| raw.parse::<i32>(),
+        |s: &mut Passthrough, v: i32| s.on_input(v),
+        |s: &mut Passthrough, _| s.on_input(-1),
+        |_: &mut Passthrough| (),
+    );
+    let c = builder.finish(Passthrough { output });
+
+    for raw in ["1", "two", "3"] {
+        tx1.try_send(raw).unwrap();
+    }
+    drop(tx1);
+
+    let mut pool = LocalPool::new();
+    assert_eq!(pool.run_until(c), Ok(()));
+    assert_eq!(pool.run_until(rx2.collect::<Vec<_>>()), vec![1, -1, 3]);
+}
+
+struct Windows {
+    watermarks: Watermarks,
+    sums: BTreeMap<u64, i32>,
+    output: Output<(u64, i32)>,
+}
+
+impl Windows {
+    fn on_item(&mut self, time: u64, v: i32) {
+        let end = (time / 10 + 1) * 10;
+        *self.sums.entry(end).or_insert(0) += v;
+        self.watermarks.schedule(end);
+    }
+
+    fn on_timer(&mut self, end: u64) {
+        let sum = self.sums.remove(&end).unwrap();
+        self.output.send((end, sum));
+    }
+}
+
+#[test]
+fn event_time_windows() {
+    let (mut tx1, rx1) = channel(4);
+    let (mut tx2, rx2) = channel(4);
+    let (tx3, mut rx3) = channel(4);
+    let mut builder = Builder::new();
+    let output = builder.new_output(tx3);
+    let watermarks = builder.new_watermarks(|s: &mut Windows, end| s.on_timer(end));
+    for rx in [rx1, rx2] {
+        builder.new_timestamped_input(
+            rx,
+            &watermarks,
+            |s: &mut Windows, time, v: i32| s.on_item(time, v),
+            |_: &mut Windows| (),
+        );
+    }
+    let c = builder.finish(Windows {
+        watermarks,
+        sums: BTreeMap::new(),
+        output,
+    });
+
+    let mut pool = LocalPool::new();
+    pool.spawner().spawn_local(c.map(drop)).unwrap();
+
+    tx1.try_send((1, 1)).unwrap();
+    tx1.try_send((12, 2)).unwrap();
+    tx2.try_send((5, 10)).unwrap();
+    // The second input holds the watermark back at 5.
+    assert_eq!(try_next(&mut pool, &mut rx3), None);
+
+    tx2.try_send((15, 20)).unwrap();
+    assert_eq!(try_next(&mut pool, &mut rx3), Some((10, 11)));
+    assert_eq!(try_next(&mut pool, &mut rx3), None);
+
+    // The last window closes once the inputs end.
+    drop(tx1);
+    drop(tx2);
+    assert_eq!(pool.run_until(rx3.collect::<Vec<_>>()), vec![(20, 22)]);
+}
+
+fn temp_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("agents-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn file_output_rotates() {
+    let dir = temp_dir("file_output_rotates");
+    let mut clock = MockClock::new(Instant::now());
+    let (mut tx1, rx1) = channel(8);
+    let options = RotationOptions::new().max_bytes(6).max_age(Duration::new(60, 0));
+    let file = FileOutput::new(dir.join("out.log"), clock.handle(), options, |v: &i32, line| {
+        line.extend_from_slice(v.to_string().as_bytes());
+        Ok(())
+    })
+    .unwrap();
+    let mut builder = Builder::new();
+    let output = builder.new_file_output(file, OutputOptions::new());
+    builder.new_input(
+        rx1,
+        |s: &mut Passthrough, v: i32| s.on_input(v),
+        |_: &mut Passthrough| (),
+    );
+    let c = builder.finish(Passthrough { output });
+
+    let mut pool = LocalPool::new();
+    pool.spawner().spawn_local(c.map(drop)).unwrap();
+    for v in [10, 11, 12] {
+        tx1.try_send(v).unwrap();
+    }
+    pool.run_until_stalled();
+    // The third file is started by age rather than size.
+    clock.advance(Duration::new(60, 0));
+    tx1.try_send(13).unwrap();
+    pool.run_until_stalled();
+
+    let read = |i: u32| std::fs::read_to_string(dir.join(format!("out.log.{}", i))).unwrap();
+    assert_eq!(read(0), "10\n11\n");
+    assert_eq!(read(1), "12\n");
+    assert_eq!(read(2), "13\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn file_output_json() {
+    let dir = temp_dir("file_output_json");
+    let clock = MockClock::new(Instant::now());
+    let path = dir.join("events.log");
+    let file = FileOutput::json(&path, clock.handle(), RotationOptions::new()).unwrap();
+    let mut builder = Builder::new();
+    let mut output = builder.new_file_output(file, OutputOptions::new());
+    output.send((1, "start".to_string()));
+    output.send((2, "stop".to_string()));
+    drop(output);
+    let c = builder.finish(());
+    assert_eq!(LocalPool::new().run_until(c), Ok(()));
+
+    let written = std::fs::read_to_string(dir.join("events.log.0")).unwrap();
+    assert_eq!(written, "[1,\"start\"]\n[2,\"stop\"]\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+// Records the agent name of every agent poll span.
+#[cfg(feature = "tracing")]
+struct PollSpans {
+    agents: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+}
+
+#[cfg(feature = "tracing")]
+impl tracing::field::Visit for PollSpans {
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        if field.name() == "agent" {
+            self.agents.lock().unwrap().push(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, _: &tracing::field::Field, _: &dyn std::fmt::Debug) {}
+}
+
+#[cfg(feature = "tracing")]
+impl tracing::Subscriber for PollSpans {
+    fn enabled(&self, _: &tracing::Metadata) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &tracing::span::Attributes) -> tracing::span::Id {
+        if span.metadata().name() == "agent_poll" {
+            span.record(&mut PollSpans { agents: self.agents.clone() });
+        }
+        tracing::span::Id::from_u64(1)
+    }
+
+    fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record) {}
+    fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+    fn event(&self, _: &tracing::Event) {}
+    fn enter(&self, _: &tracing::span::Id) {}
+    fn exit(&self, _: &tracing::span::Id) {}
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn agent_name_in_poll_spans() {
+    let (mut tx1, rx1) = channel(1);
+    let (tx2, mut rx2) = channel(1);
+    let mut builder = Builder::new();
+    builder.set_name("forwarder");
+    let output = builder.new_output(tx2);
+    builder.new_input(
+        rx1,
+        |s: &mut Passthrough, v| s.output.send(v),
+        |_: &mut Passthrough| (),
+    );
+    let c = builder.finish(Passthrough { output });
+    assert_eq!(c.name(), Some("forwarder"));
+
+    let agents = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let subscriber = PollSpans { agents: agents.clone() };
+    tracing::subscriber::with_default(subscriber, || {
+        let mut pool = LocalPool::new();
+        pool.spawner().spawn_local(c.map(drop)).unwrap();
+        pool.run_until(tx1.send(1)).unwrap();
+        assert_eq!(pool.run_until(rx2.next()), Some(1));
+    });
+
+    let agents = agents.lock().unwrap();
+    assert!(!agents.is_empty());
+    assert!(agents.iter().all(|a| a == "forwarder"));
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn unnamed_agent_in_poll_spans() {
+    let (tx, rx) = channel(1);
+    drop(tx);
+    let mut builder = Builder::new();
+    builder.new_input(rx, |_: &mut (), _: i32| (), |_: &mut ()| ());
+    let c = builder.finish(());
+
+    let agents = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let subscriber = PollSpans { agents: agents.clone() };
+    tracing::subscriber::with_default(subscriber, || {
+        assert_eq!(LocalPool::new().run_until(c), Ok(()));
+    });
+
+    let agents = agents.lock().unwrap();
+    assert!(!agents.is_empty());
+    assert!(agents.iter().all(|a| a == "<unnamed>"));
+}